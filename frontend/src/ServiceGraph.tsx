@@ -320,13 +320,20 @@ function nodeToCytoscape(node: Node): cytoscape.NodeDefinition {
       ...node,
       id: node.node_id,
       parent: node.parent_id,
-      group: isUnhealthy(
-        node.status_ok,
-        node.status_expected_error,
-        node.status_unexpected_error
-      )
-        ? "unhealthy"
-        : null,
+      // failing to be called and failing calls both make a node unhealthy
+      group:
+        isUnhealthy(
+          node.status_ok,
+          node.status_expected_error,
+          node.status_unexpected_error
+        ) ||
+        isUnhealthy(
+          node.outbound.status_ok,
+          node.outbound.status_expected_error,
+          node.outbound.status_unexpected_error
+        )
+          ? "unhealthy"
+          : null,
     },
   };
 }
@@ -8,6 +8,13 @@ export type CombinedEdge = {
   status_ok: number;
   status_expected_error: number;
   status_unexpected_error: number;
+  extrapolated: boolean;
+} & Rates;
+
+export type Rates = {
+  requests_per_minute: number;
+  error_ratio: number;
+  unexpected_error_ratio: number;
 };
 
 export type NodeType = "service" | "transaction";
@@ -22,11 +29,29 @@ export type Node = {
   status_ok: number;
   status_expected_error: number;
   status_unexpected_error: number;
+  inbound: StatusCounts;
+  outbound: StatusCounts;
+  extrapolated: boolean;
+  centrality?: Centrality;
+} & Rates;
+
+export type StatusCounts = {
+  status_ok: number;
+  status_expected_error: number;
+  status_unexpected_error: number;
+};
+
+export type Centrality = {
+  in_degree: number;
+  out_degree: number;
+  betweenness: number;
+  pagerank: number;
 };
 
 export type Graph = {
   edges: Array<CombinedEdge>;
   nodes: Array<Node>;
+  truncated: boolean;
 };
 
 // date time string
@@ -38,6 +63,7 @@ export type NodeWithLastActivity = Node & {
 
 export type ActiveNodes = {
   nodes: Array<NodeWithLastActivity>;
+  next_cursor: Uuid | null;
 };
 
 export type ServiceMapPayload = {
@@ -50,6 +76,10 @@ export type EdgeStatus = "ok" | "unexpected_error" | "expected_error";
 export type Bucket = {
   ts: string;
   n: number;
+  status_ok: number;
+  status_expected_error: number;
+  status_unexpected_error: number;
+  extrapolated: boolean;
 };
 
 export type HistogramData = {
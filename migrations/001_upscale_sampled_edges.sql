@@ -0,0 +1,22 @@
+-- Upscales sampled edges by 1 / sample_rate (saturating at the largest
+-- UInt32) and marks them as extrapolated.  Views created before sample_rate
+-- existed summed the raw counts.
+--
+-- Needs ClickHouse 21.12 or newer.  The view keeps feeding edges_by_minute
+-- while its query is replaced, so no inserts are lost.
+SET allow_experimental_alter_materialized_view_structure = 1;
+
+ALTER TABLE servicegraph.edges_by_minute_mv MODIFY QUERY
+SELECT
+    project_id,
+    toStartOfMinute(ts) AS ts,
+    from_node_id,
+    to_node_id,
+    argMax(description, ts) as description,
+    argMax(class, ts) as class,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 1) as status_ok,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 2) as status_expected_error,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 3) as status_unexpected_error,
+    max(toUInt8(sample_rate < 1)) as extrapolated
+FROM servicegraph.edges
+GROUP BY project_id, from_node_id, to_node_id, ts;
@@ -4,10 +4,13 @@ import json
 import threading
 import atexit
 import socket
+import random
+import gzip
 
 from datetime import datetime
 from contextvars import ContextVar, copy_context
 from contextlib import contextmanager
+from urllib.error import HTTPError
 from urllib.request import urlopen, Request
 
 SERVICE_NS = uuid.UUID("50e1147a-2643-4b97-a0bd-be87f84851c3")
@@ -26,6 +29,8 @@ class Client(object):
         self.known_nodes = {}
         self._lock = threading.Lock()
         self.instrumentations_disabled = False
+        self.max_retries = 3
+        self.sample_rate = 1.0
 
         self._service_id = ContextVar("service_id")
         self._transaction_id = ContextVar("transaction_id")
@@ -126,30 +131,50 @@ class Client(object):
                     "to_node_id": to_node,
                     "status": status,
                     "n": n,
+                    "sample_rate": self.sample_rate,
                 }
                 if edge_meta:
                     edge.update(edge_meta)
                 edges.append(edge)
 
         if nodes or edges:
-            with self.disabled_instrumentations():
-                urlopen(
-                    Request(
-                        url="http://%s:%d/submit/" % (self.host, self.port),
-                        headers={"content-type": "application/json"},
-                        method="POST",
-                        data=bytes(
-                            json.dumps(
-                                {
-                                    "nodes": nodes,
-                                    "edges": edges,
-                                    "project_id": self.project_id,
-                                }
-                            ),
-                            "utf-8",
-                        ),
-                    )
+            # the batch id lets the server drop retries it already stored
+            data = gzip.compress(
+                bytes(
+                    json.dumps(
+                        {
+                            "nodes": nodes,
+                            "edges": edges,
+                            "project_id": self.project_id,
+                            "batch_id": str(uuid.uuid4()),
+                        }
+                    ),
+                    "utf-8",
                 )
+            )
+            with self.disabled_instrumentations():
+                for attempt in range(self.max_retries + 1):
+                    try:
+                        urlopen(
+                            Request(
+                                url="http://%s:%d/submit/" % (self.host, self.port),
+                                headers={
+                                    "content-type": "application/json",
+                                    "content-encoding": "gzip",
+                                },
+                                method="POST",
+                                data=data,
+                            )
+                        )
+                        break
+                    except OSError as e:
+                        # client errors won't go away by sending the batch again,
+                        # 409 means the same batch is still being written
+                        if isinstance(e, HTTPError) and e.code < 500 and e.code != 409:
+                            raise
+                        if attempt == self.max_retries:
+                            raise
+                        time.sleep(0.5 * 2 ** attempt)
 
         self.pending_edges_meta = {}
         self.pending_edges = {}
@@ -186,6 +211,9 @@ class Client(object):
     def report_edge(
         self, from_node, to_node, status="ok", n=1, description=None, class_=None
     ):
+        if self.sample_rate < 1.0 and random.random() >= self.sample_rate:
+            return
+
         t = time.time() // 60 * 60
         bucket = (str(from_node), str(to_node), t)
 
@@ -361,7 +389,7 @@ _patch_flask()
 _register_atexit()
 
 
-def init(host=None, port=None, project_id=None, service_ns=None):
+def init(host=None, port=None, project_id=None, service_ns=None, sample_rate=None):
     if host is not None:
         client.host = host
     if port is not None:
@@ -370,3 +398,5 @@ def init(host=None, port=None, project_id=None, service_ns=None):
         client.project_id = project_id
     if service_ns is not None:
         client.service_ns = service_ns
+    if sample_rate is not None:
+        client.sample_rate = sample_rate
@@ -0,0 +1,192 @@
+use std::convert::TryFrom;
+use std::io::Read;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use prost::Message;
+use rocket::data::{self, ByteUnit, Data, FromData, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::outcome::Outcome;
+use rocket::Request;
+
+use crate::error::Error;
+use crate::payloads::SubmitData;
+use crate::proto;
+
+/// Compression schemes understood in `Content-Encoding`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn from_header(value: Option<&str>) -> Result<Encoding, Error> {
+        match value.map(|x| x.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("identity") => Ok(Encoding::Identity),
+            Some("gzip") | Some("x-gzip") => Ok(Encoding::Gzip),
+            Some("deflate") => Ok(Encoding::Deflate),
+            Some("zstd") => Ok(Encoding::Zstd),
+            Some(other) => Err(anyhow::anyhow!("unsupported content encoding {}", other)),
+        }
+    }
+}
+
+/// Serialization formats of a submission selected by `Content-Type`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Protobuf,
+}
+
+impl Format {
+    pub fn from_content_type(content_type: Option<&ContentType>) -> Result<Format, Error> {
+        let content_type = match content_type {
+            Some(content_type) => content_type,
+            None => return Ok(Format::Json),
+        };
+        if content_type.is_json() {
+            return Ok(Format::Json);
+        }
+        match (content_type.top().as_str(), content_type.sub().as_str()) {
+            ("application", "msgpack") | ("application", "x-msgpack") => Ok(Format::MessagePack),
+            ("application", "protobuf")
+            | ("application", "x-protobuf")
+            | ("application", "vnd.google.protobuf") => Ok(Format::Protobuf),
+            _ => Err(anyhow::anyhow!("unsupported content type {}", content_type)),
+        }
+    }
+}
+
+/// Decompresses a body, failing if it grows beyond `limit` bytes.
+pub fn decompress(encoding: Encoding, body: Vec<u8>, limit: u64) -> Result<Vec<u8>, Error> {
+    let reader: Box<dyn Read> = match encoding {
+        Encoding::Identity => return Ok(body),
+        Encoding::Gzip => Box::new(GzDecoder::new(&body[..])),
+        Encoding::Deflate => Box::new(ZlibDecoder::new(&body[..])),
+        Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(&body[..])?),
+    };
+    let mut rv = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut rv)?;
+    if rv.len() as u64 > limit {
+        return Err(anyhow::anyhow!("decompressed body exceeds {} bytes", limit));
+    }
+    Ok(rv)
+}
+
+pub fn decode(format: Format, body: &[u8]) -> Result<SubmitData, Error> {
+    Ok(match format {
+        Format::Json => serde_json::from_slice(body)?,
+        Format::MessagePack => {
+            // uuids and timestamps are strings just like in the JSON payload
+            let mut deserializer = rmp_serde::Deserializer::new(body).with_human_readable();
+            serde::Deserialize::deserialize(&mut deserializer)?
+        }
+        Format::Protobuf => SubmitData::try_from(proto::SubmitData::decode(body)?)?,
+    })
+}
+
+/// A submission body in any of the supported encodings and formats.
+pub struct SubmitBody(pub SubmitData);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for SubmitBody {
+    type Error = Error;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = req.limits().get("submit").unwrap_or_else(|| 16.mebibytes());
+        let decompressed_limit: ByteUnit = req
+            .limits()
+            .get("submit-decompressed")
+            .unwrap_or_else(|| 64.mebibytes());
+
+        let encoding = match Encoding::from_header(req.headers().get_one("Content-Encoding")) {
+            Ok(encoding) => encoding,
+            Err(err) => return Outcome::Failure((Status::UnsupportedMediaType, err)),
+        };
+        let format = match Format::from_content_type(req.content_type()) {
+            Ok(format) => format,
+            Err(err) => return Outcome::Failure((Status::UnsupportedMediaType, err)),
+        };
+
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                let err = anyhow::anyhow!("body exceeds {} bytes", limit);
+                return Outcome::Failure((Status::PayloadTooLarge, err));
+            }
+            Err(err) => return Outcome::Failure((Status::BadRequest, err.into())),
+        };
+        // inflating and parsing up to `submit-decompressed` bytes takes a
+        // while, keep it off the async workers
+        let decoded = tokio::task::spawn_blocking(move || {
+            let body = decompress(encoding, body, decompressed_limit.as_u64())
+                .map_err(|err| (Status::PayloadTooLarge, err))?;
+            decode(format, &body).map_err(|err| (Status::BadRequest, err))
+        })
+        .await;
+        match decoded {
+            Ok(Ok(data)) => Outcome::Success(SubmitBody(data)),
+            Ok(Err(failure)) => Outcome::Failure(failure),
+            Err(err) => Outcome::Failure((Status::InternalServerError, err.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    const PAYLOAD: &str = r#"{"project_id": 42, "edges": [{"ts": "2021-06-09T00:00:00Z", "from_node_id": "418f3d00-ba14-42eb-98b8-5f3fb1b975c8", "to_node_id": "5042546b-07a0-41d4-a73c-9138722eebb4", "status": "ok", "n": 3, "description": null, "class": null}]}"#;
+
+    #[test]
+    fn test_decompress() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(PAYLOAD.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let body = decompress(Encoding::Gzip, gzipped.clone(), 1024).unwrap();
+        assert_eq!(body, PAYLOAD.as_bytes());
+        assert!(decompress(Encoding::Gzip, gzipped, 16).is_err());
+
+        let zstded = zstd::encode_all(PAYLOAD.as_bytes(), 0).unwrap();
+        let body = decompress(Encoding::Zstd, zstded, 1024).unwrap();
+        assert_eq!(decode(Format::Json, &body).unwrap().edges.len(), 1);
+    }
+
+    #[test]
+    fn test_decode_formats() {
+        let data: SubmitData = serde_json::from_str(PAYLOAD).unwrap();
+
+        let packed =
+            rmp_serde::to_vec_named(&serde_json::from_str::<serde_json::Value>(PAYLOAD).unwrap())
+                .unwrap();
+        let decoded = decode(Format::MessagePack, &packed).unwrap();
+        assert_eq!(decoded.edges[0].from_node_id, data.edges[0].from_node_id);
+
+        let message = proto::SubmitData {
+            project_id: 42,
+            nodes: vec![],
+            edges: vec![proto::Edge {
+                ts: data.edges[0].ts.timestamp_millis(),
+                from_node_id: data.edges[0].from_node_id.as_bytes().to_vec(),
+                to_node_id: data.edges[0].to_node_id.as_bytes().to_vec(),
+                status: proto::EdgeStatus::Ok as i32,
+                n: 3,
+                description: None,
+                class: None,
+                sample_rate: None,
+            }],
+            batch_id: None,
+        };
+        let decoded = decode(Format::Protobuf, &message.encode_to_vec()).unwrap();
+        assert_eq!(decoded.project_id, 42);
+        assert_eq!(decoded.edges[0].ts, data.edges[0].ts);
+        assert_eq!(decoded.edges[0].to_node_id, data.edges[0].to_node_id);
+    }
+}
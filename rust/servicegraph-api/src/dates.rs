@@ -0,0 +1,404 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{
+    DateTime, Duration, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::Error;
+
+/// Relative dates cannot reach further back than this many days.
+const MAX_RELATIVE_DAYS: i64 = 100 * 365;
+
+/// A bound of a queried range, either absolute or relative to now.
+///
+/// Parsed from an RFC 3339 date, `now`, `today`, `yesterday` or an offset
+/// into the past like `-30s`, `-15m`, `-2h`, `-7d` or `-1w`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DateSpec {
+    Absolute(DateTime<Utc>),
+    /// How long before now.
+    Ago(Duration),
+    /// Midnight at the start of the current day in the query's timezone.
+    Today,
+    /// Midnight at the start of the previous day in the query's timezone.
+    Yesterday,
+}
+
+impl DateSpec {
+    /// Returns the date this resolves to at `now` for a viewer in `tz`.
+    pub fn resolve(self, now: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        match self {
+            DateSpec::Absolute(date) => date,
+            DateSpec::Ago(duration) => now - duration,
+            DateSpec::Today => start_of_day(now.with_timezone(&tz).naive_local().date(), tz),
+            DateSpec::Yesterday => {
+                let today = now.with_timezone(&tz).naive_local().date();
+                start_of_day(today.pred_opt().unwrap_or(today), tz)
+            }
+        }
+    }
+}
+
+/// The first instant of `date` in `tz`, which is not necessarily midnight on
+/// days where the clocks skip it.
+fn start_of_day(date: NaiveDate, tz: Tz) -> DateTime<Utc> {
+    let hours: Vec<NaiveDateTime> = (0..24)
+        .filter_map(|hour| date.and_hms_opt(hour, 0, 0))
+        .collect();
+    hours
+        .iter()
+        .find_map(|x| tz.from_local_datetime(x).earliest())
+        .map(|x| x.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&hours[0]))
+}
+
+fn parse_offset(s: &str) -> Option<Duration> {
+    let s = s.strip_prefix('-')?;
+    if s.len() < 2 {
+        return None;
+    }
+    let (amount, unit) = s.split_at(s.len() - 1);
+    let amount: i64 = amount.parse().ok().filter(|x| *x >= 0)?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+    amount
+        .checked_mul(seconds)
+        .filter(|x| *x <= MAX_RELATIVE_DAYS * 60 * 60 * 24)
+        .map(Duration::seconds)
+}
+
+impl FromStr for DateSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DateSpec, Error> {
+        match s {
+            "now" => return Ok(DateSpec::Ago(Duration::zero())),
+            "today" => return Ok(DateSpec::Today),
+            "yesterday" => return Ok(DateSpec::Yesterday),
+            _ => {}
+        }
+        if let Some(duration) = parse_offset(s) {
+            return Ok(DateSpec::Ago(duration));
+        }
+        DateTime::parse_from_rfc3339(s)
+            .map(|x| DateSpec::Absolute(x.with_timezone(&Utc)))
+            .map_err(|_| anyhow::anyhow!("invalid date {:?}", s))
+    }
+}
+
+impl fmt::Display for DateSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DateSpec::Absolute(date) => {
+                write!(f, "{}", date.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            DateSpec::Ago(duration) if duration.is_zero() => write!(f, "now"),
+            DateSpec::Ago(duration) => write!(f, "-{}s", duration.num_seconds()),
+            DateSpec::Today => write!(f, "today"),
+            DateSpec::Yesterday => write!(f, "yesterday"),
+        }
+    }
+}
+
+impl From<DateTime<Utc>> for DateSpec {
+    fn from(date: DateTime<Utc>) -> DateSpec {
+        DateSpec::Absolute(date)
+    }
+}
+
+impl Serialize for DateSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DateSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DateSpec, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GranularityUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+}
+
+/// Width of histogram buckets, parsed from `10s`, `1m`, `5m`, `1h`, `1d` and
+/// such.  Whole minutes given in seconds are taken as minutes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Granularity {
+    pub amount: u32,
+    pub unit: GranularityUnit,
+}
+
+impl Granularity {
+    pub fn seconds(self) -> u32 {
+        self.amount
+            * match self.unit {
+                GranularityUnit::Second => 1,
+                GranularityUnit::Minute => 60,
+                GranularityUnit::Hour => 60 * 60,
+                GranularityUnit::Day => 60 * 60 * 24,
+            }
+    }
+
+    /// Number of buckets `buckets` returns for the range.
+    pub fn bucket_count(self, start: DateTime<Utc>, end: DateTime<Utc>, tz: Tz) -> i64 {
+        match self.unit {
+            GranularityUnit::Second | GranularityUnit::Minute => {
+                let first = self.floor(start, tz);
+                if end < first {
+                    return 0;
+                }
+                end.signed_duration_since(first).num_seconds() / self.seconds() as i64 + 1
+            }
+            // hours and days are not all equally long
+            GranularityUnit::Hour | GranularityUnit::Day => {
+                self.buckets(start, end, tz).len() as i64
+            }
+        }
+    }
+
+    /// Start of the bucket `date` falls into, aligned like ClickHouse's
+    /// `toStartOfInterval`: minutes to the epoch, hours within the local day
+    /// and days to the local days since the epoch.
+    pub fn floor(self, date: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        let local = date.with_timezone(&tz).naive_local();
+        let amount = self.amount as i64;
+        let floored = match self.unit {
+            GranularityUnit::Second | GranularityUnit::Minute => {
+                let step = self.seconds() as i64;
+                Utc.timestamp_opt(date.timestamp().div_euclid(step) * step, 0)
+                    .single()
+            }
+            GranularityUnit::Hour => local
+                .date()
+                .and_hms_opt(local.hour() / self.amount * self.amount, 0, 0)
+                .and_then(|x| tz.from_local_datetime(&x).earliest())
+                .map(|x| x.with_timezone(&Utc)),
+            GranularityUnit::Day => {
+                let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap_or(local.date());
+                let days = local.date().signed_duration_since(epoch).num_days();
+                Some(start_of_day(
+                    epoch + Duration::days(days - days.rem_euclid(amount)),
+                    tz,
+                ))
+            }
+        };
+        floored.unwrap_or(date)
+    }
+
+    /// Starts of all buckets overlapping the range from `start` to `end`.
+    pub fn buckets(self, start: DateTime<Utc>, end: DateTime<Utc>, tz: Tz) -> Vec<DateTime<Utc>> {
+        let step = Duration::seconds(self.seconds() as i64);
+        // a bit past the next bucket start so that days with a daylight
+        // saving change still land in the next bucket
+        let skip = step + (step / 2).min(Duration::hours(2));
+        let mut rv = Vec::new();
+        let mut bucket = self.floor(start, tz);
+        while bucket <= end {
+            rv.push(bucket);
+            let next = self.floor(bucket + skip, tz);
+            if next <= bucket {
+                break;
+            }
+            bucket = next;
+        }
+        rv
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Granularity, Error> {
+        let invalid = || anyhow::anyhow!("invalid granularity {:?}", s);
+        if s.len() < 2 {
+            return Err(invalid());
+        }
+        let (amount, unit) = s.split_at(s.len() - 1);
+        let amount: u32 = amount.parse().map_err(|_| invalid())?;
+        let (amount, unit) = match unit {
+            "s" if amount / 60 * 60 == amount => (amount / 60, GranularityUnit::Minute),
+            "s" => (amount, GranularityUnit::Second),
+            "m" => (amount, GranularityUnit::Minute),
+            "h" => (amount, GranularityUnit::Hour),
+            "d" => (amount, GranularityUnit::Day),
+            _ => return Err(invalid()),
+        };
+        // hour buckets are aligned within a day
+        if amount == 0 || (unit == GranularityUnit::Hour && amount > 24) {
+            return Err(invalid());
+        }
+        Ok(Granularity { amount, unit })
+    }
+}
+
+impl fmt::Display for Granularity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.unit {
+            GranularityUnit::Second => "s",
+            GranularityUnit::Minute => "m",
+            GranularityUnit::Hour => "h",
+            GranularityUnit::Day => "d",
+        };
+        write!(f, "{}{}", self.amount, unit)
+    }
+}
+
+impl Serialize for Granularity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Granularity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Granularity, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "-15m".parse::<DateSpec>().unwrap(),
+            DateSpec::Ago(Duration::minutes(15))
+        );
+        assert_eq!(
+            "-7d".parse::<DateSpec>().unwrap(),
+            DateSpec::Ago(Duration::days(7))
+        );
+        assert_eq!(
+            "now".parse::<DateSpec>().unwrap(),
+            DateSpec::Ago(Duration::zero())
+        );
+        assert_eq!(
+            "2021-06-09T02:00:00+02:00".parse::<DateSpec>().unwrap(),
+            DateSpec::Absolute(date("2021-06-09T00:00:00Z"))
+        );
+        for invalid in &["15m", "-m", "-15x", "-99999999999w", "tomorrow", ""] {
+            assert!(invalid.parse::<DateSpec>().is_err(), "{}", invalid);
+        }
+
+        for spec in &["-900s", "today", "now", "2021-06-09T00:00:00Z"] {
+            assert_eq!(&spec.parse::<DateSpec>().unwrap().to_string(), spec);
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let now = date("2021-06-09T22:30:00Z");
+        assert_eq!(
+            DateSpec::Ago(Duration::hours(1)).resolve(now, Tz::UTC),
+            date("2021-06-09T21:30:00Z")
+        );
+        assert_eq!(
+            DateSpec::Today.resolve(now, Tz::UTC),
+            date("2021-06-09T00:00:00Z")
+        );
+        // already the 10th in Vienna (UTC+2)
+        assert_eq!(
+            DateSpec::Today.resolve(now, Tz::Europe__Vienna),
+            date("2021-06-09T22:00:00Z")
+        );
+        assert_eq!(
+            DateSpec::Yesterday.resolve(now, Tz::America__New_York),
+            date("2021-06-08T04:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_granularity() {
+        let granularity: Granularity = "5m".parse().unwrap();
+        assert_eq!(granularity.seconds(), 300);
+        assert_eq!("120s".parse::<Granularity>().unwrap().to_string(), "2m");
+        assert_eq!("10s".parse::<Granularity>().unwrap().seconds(), 10);
+        for invalid in &["0s", "0m", "25h", "1y", "m", "-1h"] {
+            assert!(invalid.parse::<Granularity>().is_err(), "{}", invalid);
+        }
+
+        let start = date("2021-06-09T10:03:00Z");
+        let end = date("2021-06-09T10:20:00Z");
+        assert_eq!(
+            granularity.buckets(start, end, Tz::UTC),
+            vec![
+                date("2021-06-09T10:00:00Z"),
+                date("2021-06-09T10:05:00Z"),
+                date("2021-06-09T10:10:00Z"),
+                date("2021-06-09T10:15:00Z"),
+                date("2021-06-09T10:20:00Z"),
+            ]
+        );
+        // the first bucket starts before the range
+        assert_eq!(granularity.bucket_count(start, end, Tz::UTC), 5);
+
+        let seconds: Granularity = "10s".parse().unwrap();
+        let start = date("2021-06-09T10:00:05Z");
+        let end = date("2021-06-09T10:00:30Z");
+        assert_eq!(
+            seconds.buckets(start, end, Tz::UTC),
+            vec![
+                date("2021-06-09T10:00:00Z"),
+                date("2021-06-09T10:00:10Z"),
+                date("2021-06-09T10:00:20Z"),
+                date("2021-06-09T10:00:30Z"),
+            ]
+        );
+        assert_eq!(seconds.bucket_count(start, end, Tz::UTC), 4);
+
+        // days follow local midnight, including the 23 hour day in March
+        let day: Granularity = "1d".parse().unwrap();
+        let buckets = day.buckets(
+            date("2021-03-27T12:00:00Z"),
+            date("2021-03-29T12:00:00Z"),
+            Tz::Europe__Vienna,
+        );
+        assert_eq!(
+            buckets,
+            vec![
+                date("2021-03-26T23:00:00Z"),
+                date("2021-03-27T23:00:00Z"),
+                date("2021-03-28T22:00:00Z"),
+            ]
+        );
+        assert_eq!(
+            day.bucket_count(
+                date("2021-03-27T12:00:00Z"),
+                date("2021-03-29T12:00:00Z"),
+                Tz::Europe__Vienna,
+            ),
+            3
+        );
+
+        let hours: Granularity = "6h".parse().unwrap();
+        assert_eq!(
+            hours.floor(date("2021-06-09T22:30:00Z"), Tz::Europe__Vienna),
+            date("2021-06-09T22:00:00Z")
+        );
+    }
+}
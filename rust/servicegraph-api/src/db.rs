@@ -1,4 +1,4 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Write;
 
 use chrono::Utc;
@@ -9,10 +9,17 @@ use clickhouse_rs::{Block, ClientHandle, Pool};
 use lazy_static::lazy_static;
 use uuid::Uuid;
 
+use crate::dates::{Granularity, GranularityUnit};
 use crate::error::Error;
+use crate::graph;
+use crate::limits::ResponseLimits;
 use crate::payloads::{
-    ActiveNodes, Bucket, CombinedEdge, CommonQueryParams, Edge, EdgeStatus, Graph,
-    GraphQueryParams, Histogram, Node, NodeActivity, NodeQueryParams, NodeType, NodeWithStatus,
+    ActiveNodes, AttributeFilter, BlastRadius, Bucket, CombinedEdge, CommonQueryParams,
+    CycleQueryParams, Cycles, Edge, EdgeStatus, Graph, GraphDiff, GraphDiffQueryParams,
+    GraphQueryParams, Histogram, HistogramQueryParams, ImpactQueryParams, NeighborhoodQueryParams,
+    Node, NodeActivity, NodeDependency, NodeDetail, NodeQueryParams, NodeSearchParams,
+    NodeSearchResult, NodeSearchResults, NodeType, NodeWithStatus, PathQueryParams, Paths, Rates,
+    RootCauses, StatusCounts, StoredSubmission, TrafficFilter,
 };
 
 lazy_static! {
@@ -53,6 +60,15 @@ pub async fn register_edges(
     client: &mut ClientHandle,
     project_id: u64,
     edges: &[Edge],
+) -> Result<(), Error> {
+    register_edges_into(client, "edges", project_id, edges).await
+}
+
+async fn register_edges_into(
+    client: &mut ClientHandle,
+    table: &str,
+    project_id: u64,
+    edges: &[Edge],
 ) -> Result<(), Error> {
     let block = Block::new()
         .column("project_id", vec![project_id; edges.len()])
@@ -62,20 +78,47 @@ pub async fn register_edges(
         .column("status", colvec!(edges, |x| x.status.as_u8()))
         .column("description", colvec!(edges, |x| x.description.clone()))
         .column("class", colvec!(edges, |x| x.class.clone()))
-        .column("n", colvec!(edges, |x| x.n.min(1)));
-    client.insert("edges", block).await?;
+        // an edge stands for at least one call
+        .column("n", colvec!(edges, |x| x.n.max(1)))
+        .column("sample_rate", colvec!(edges, |x| x.sample_rate as f32));
+    client.insert(table, block).await?;
+    Ok(())
+}
+
+pub async fn register_submission(
+    client: &mut ClientHandle,
+    submission: &StoredSubmission,
+) -> Result<(), Error> {
+    let data = &submission.data;
+    if !data.nodes.is_empty() {
+        register_nodes(client, data.project_id, &data.nodes).await?;
+    }
+    if !data.edges.is_empty() {
+        register_edges(client, data.project_id, &data.edges).await?;
+    }
+    if !submission.backfill_edges.is_empty() {
+        register_edges_into(
+            client,
+            "edges_backfill",
+            data.project_id,
+            &submission.backfill_edges,
+        )
+        .await?;
+    }
     Ok(())
 }
 
 fn default_date_range(params: &CommonQueryParams) -> (DateTime<Utc>, DateTime<Utc>) {
+    let now = Utc::now();
+    let tz = params.timezone.unwrap_or(Tz::UTC);
     (
         match params.start_date {
-            Some(s) => s,
-            None => Utc::now() - Duration::hours(1),
+            Some(s) => s.resolve(now, tz),
+            None => now - Duration::hours(1),
         },
         match params.end_date {
-            Some(s) => s,
-            None => Utc::now(),
+            Some(s) => s.resolve(now, tz),
+            None => now,
         },
     )
 }
@@ -115,6 +158,61 @@ fn get_edge_post_filter(edge_statuses: &BTreeSet<EdgeStatus>) -> Result<String,
     Ok(filter)
 }
 
+/// Quotes a string for use as a ClickHouse string literal.
+fn quote_string(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Turns a glob with `*` and `?` into a `LIKE` pattern, plain strings match
+/// as a prefix.
+fn like_pattern(glob: &str) -> String {
+    let mut pattern = String::new();
+    for c in glob.chars() {
+        match c {
+            '\\' | '%' | '_' => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            c => pattern.push(c),
+        }
+    }
+    if !glob.contains(['*', '?']) {
+        pattern.push('%');
+    }
+    pattern
+}
+
+/// Filters the aggregated edges `t` by their latest attributes.
+fn get_attribute_filter(filter: &AttributeFilter) -> String {
+    let mut clauses = Vec::new();
+    if let Some(ref node_name) = filter.node_name {
+        let pattern = quote_string(&like_pattern(node_name));
+        clauses.push(format!(
+            "(t.from_node_name LIKE {pattern} OR t.to_node_name LIKE {pattern})",
+            pattern = pattern
+        ));
+    }
+    if let Some(ref node_class) = filter.node_class {
+        let class = quote_string(node_class);
+        clauses.push(format!(
+            "(t.from_node_class = {class} OR t.to_node_class = {class})",
+            class = class
+        ));
+    }
+    if let Some(ref edge_class) = filter.edge_class {
+        clauses.push(format!("t.edge_class = {}", quote_string(edge_class)));
+    }
+    if let Some(ref edge_description) = filter.edge_description {
+        clauses.push(format!(
+            "position(t.edge_description, {}) > 0",
+            quote_string(edge_description)
+        ));
+    }
+    clauses.join(" AND ")
+}
+
 fn and_if_filter(filter: &String) -> &str {
     return if filter.is_empty() { "" } else { "AND " };
 }
@@ -133,12 +231,233 @@ fn node_from_row(row: &Row<Complex>, prefix: &str) -> Result<Node, Error> {
 pub async fn query_graph(
     client: &mut ClientHandle,
     params: &GraphQueryParams,
+) -> Result<Graph, Error> {
+    query_graph_filtered(client, params, "", None).await
+}
+
+/// Like `query_graph` but returns at most as much as `limits` allow.
+pub async fn query_graph_limited(
+    client: &mut ClientHandle,
+    params: &GraphQueryParams,
+    limits: &ResponseLimits,
+) -> Result<Graph, Error> {
+    // traffic filters and centrality need to see all edges, otherwise only
+    // the busiest are fetched to begin with
+    let mut graph = if params.traffic.is_empty() && !params.with_centrality {
+        query_graph_filtered(client, params, "", Some(limits.max_graph_edges)).await?
+    } else {
+        let graph = query_graph(client, params).await?;
+        filter_traffic(graph, params, &params.traffic, params.roll_up_transactions)
+    };
+    if params.with_centrality {
+        graph = add_centrality(graph, limits).await?;
+    }
+    Ok(graph::truncate(
+        graph,
+        limits.max_graph_nodes,
+        limits.max_graph_edges,
+    ))
+}
+
+/// Fills in the centrality scores of a graph on a blocking thread.
+///
+/// Betweenness takes time proportional to nodes times edges, graphs with
+/// more than `max_centrality_nodes` nodes are refused.
+pub async fn add_centrality(graph: Graph, limits: &ResponseLimits) -> Result<Graph, Error> {
+    if graph.nodes.len() > limits.max_centrality_nodes {
+        return Err(anyhow::anyhow!(
+            "centrality is computed for at most {} nodes but the graph has {}, narrow down the query",
+            limits.max_centrality_nodes,
+            graph.nodes.len()
+        ));
+    }
+    Ok(tokio::task::spawn_blocking(move || {
+        let mut graph = graph;
+        graph::add_centrality(&mut graph);
+        graph
+    })
+    .await?)
+}
+
+/// Length of the queried window in minutes.
+fn window_minutes(params: &CommonQueryParams) -> f64 {
+    let (start_date_bound, end_date_bound) = default_date_range(params);
+    end_date_bound
+        .signed_duration_since(start_date_bound)
+        .num_seconds() as f64
+        / 60.0
+}
+
+/// Applies a traffic filter to a graph queried over the window of `params`
+/// and recomputes the statuses and rates of the remaining nodes.
+pub fn filter_traffic(
+    graph: Graph,
+    params: &CommonQueryParams,
+    filter: &TrafficFilter,
+    roll_up_transactions: bool,
+) -> Graph {
+    if filter.is_empty() {
+        return graph;
+    }
+    let mut graph = graph::filter_traffic(graph, filter, window_minutes(params));
+    if roll_up_transactions {
+        graph::roll_up_transactions(&mut graph);
+    }
+    add_rates(&mut graph, params);
+    graph
+}
+
+/// Computes the rates of a graph queried over the window of `params`.
+pub fn add_rates(graph: &mut Graph, params: &CommonQueryParams) {
+    graph::add_rates(graph, window_minutes(params));
+}
+
+pub async fn query_neighborhood(
+    client: &mut ClientHandle,
+    params: &NeighborhoodQueryParams,
+) -> Result<Graph, Error> {
+    let graph = query_graph(client, params).await?;
+    Ok(graph::neighborhood(
+        graph,
+        params.node_id,
+        params.upstream_hops,
+        params.downstream_hops,
+    ))
+}
+
+pub async fn query_paths(
+    client: &mut ClientHandle,
+    params: &PathQueryParams,
+) -> Result<Paths, Error> {
+    let graph = query_graph(client, params).await?;
+    Ok(graph::paths(
+        graph,
+        params.from_node_id,
+        params.to_node_id,
+        params.max_hops,
+        params.limit,
+    ))
+}
+
+pub async fn query_blast_radius(
+    client: &mut ClientHandle,
+    params: &ImpactQueryParams,
+) -> Result<BlastRadius, Error> {
+    let graph = query_graph(client, params).await?;
+    Ok(graph::blast_radius(graph, params.node_id))
+}
+
+pub async fn query_cycles(
+    client: &mut ClientHandle,
+    params: &CycleQueryParams,
+) -> Result<Cycles, Error> {
+    let mut graph = query_graph(client, params).await?;
+    add_missing_parents(client, params.project_id, &mut graph).await?;
+    Ok(graph::cycles(graph, params.ignore_self_loops))
+}
+
+pub async fn query_root_causes(
+    client: &mut ClientHandle,
+    params: &GraphQueryParams,
+) -> Result<RootCauses, Error> {
+    let graph = query_graph(client, params).await?;
+    Ok(graph::root_causes(graph))
+}
+
+pub async fn query_graph_diff(
+    client: &mut ClientHandle,
+    params: &GraphDiffQueryParams,
+) -> Result<GraphDiff, Error> {
+    let (before, after) = params.windows();
+    let before = query_graph(client, &before).await?;
+    let after = query_graph(client, &after).await?;
+    Ok(graph::diff(
+        before,
+        after,
+        params.volume_threshold,
+        params.error_ratio_threshold,
+    ))
+}
+
+/// Adds the parents of transactions that are not part of `graph` yet.
+pub async fn add_missing_parents(
+    client: &mut ClientHandle,
+    project_id: u64,
+    graph: &mut Graph,
+) -> Result<(), Error> {
+    let known: HashSet<Uuid> = graph.nodes.iter().map(|x| x.node.node_id).collect();
+    let missing: BTreeSet<Uuid> = graph
+        .nodes
+        .iter()
+        .filter_map(|x| x.node.parent_id)
+        .filter(|x| !known.contains(x))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let ids: Vec<String> = missing
+        .iter()
+        .map(|id| format!("toUUID('{}')", id))
+        .collect();
+    let parents = query_nodes(
+        client,
+        project_id,
+        &format!("node_id IN ({})", ids.join(", ")),
+    )
+    .await?;
+    graph
+        .nodes
+        .extend(parents.into_iter().map(|node| NodeWithStatus {
+            node,
+            inbound: StatusCounts::default(),
+            outbound: StatusCounts::default(),
+            extrapolated: false,
+            centrality: None,
+            rates: Rates::default(),
+        }));
+    Ok(())
+}
+
+/// The latest version of every node of a project.  `nodes` is a
+/// ReplacingMergeTree, until parts are merged it holds a row per update.
+fn latest_nodes(project_id: u64) -> String {
+    format!(
+        r#"
+            SELECT
+                node_id,
+                argMax(node_type, ts) as node_type,
+                argMax(name, ts) as name,
+                argMax(parent_id, ts) as parent_id,
+                argMax(description, ts) as description,
+                argMax(class, ts) as class
+            FROM nodes
+            WHERE project_id = {}
+            GROUP BY node_id"#,
+        project_id
+    )
+}
+
+/// Like `query_graph` but only considers edges matching `edge_filter` and
+/// fetches at most `limit` edges, the busiest ones.
+async fn query_graph_filtered(
+    client: &mut ClientHandle,
+    params: &GraphQueryParams,
+    edge_filter: &str,
+    limit: Option<usize>,
 ) -> Result<Graph, Error> {
     let (start_date_bound, end_date_bound) = default_date_range(params);
 
     let from_node_filter = get_node_filter(&params.from_types, "from_node.node_type")?;
     let to_node_filter = get_node_filter(&params.to_types, "to_node.node_type")?;
-    let edge_post_filter = get_edge_post_filter(&params.edge_statuses)?;
+    let mut edge_post_filter = get_edge_post_filter(&params.edge_statuses)?;
+    let attribute_filter = get_attribute_filter(&params.attributes);
+    if !attribute_filter.is_empty() {
+        if !edge_post_filter.is_empty() {
+            edge_post_filter.push_str(" AND ");
+        }
+        edge_post_filter.push_str(&attribute_filter);
+    }
     let base_query = format!(
         r#"
         SELECT
@@ -146,39 +465,44 @@ pub async fn query_graph(
             from_node.name as from_node_name,
             from_node.node_type as from_node_type,
             from_node.parent_id as from_node_parent_id,
-            argMax(from_node.description, from_node.ts) as from_node_description,
-            argMax(from_node.class, from_node.ts) as from_node_class,
+            from_node.description as from_node_description,
+            from_node.class as from_node_class,
             edges.to_node_id as to_node_id,
             to_node.name as to_node_name,
             to_node.node_type as to_node_type,
             to_node.parent_id as to_node_parent_id,
-            argMax(to_node.description, to_node.ts) as to_node_description,
-            argMax(to_node.class, to_node.ts) as to_node_class,
+            to_node.description as to_node_description,
+            to_node.class as to_node_class,
             argMax(edges.description, edges.ts) as edge_description,
             argMax(edges.class, edges.ts) as edge_class,
-            toUInt32(sumIfMerge(edges.status_ok)) as status_ok,
-            toUInt32(sumIfMerge(edges.status_expected_error)) as status_expected_error,
-            toUInt32(sumIfMerge(edges.status_unexpected_error)) as status_unexpected_error
-       FROM edges_by_minute_mv edges
-       JOIN nodes from_node
+            sumIfMerge(edges.status_ok) as status_ok,
+            sumIfMerge(edges.status_expected_error) as status_expected_error,
+            sumIfMerge(edges.status_unexpected_error) as status_unexpected_error,
+            max(edges.extrapolated) as extrapolated
+       FROM edges_by_minute edges
+       JOIN ({latest_nodes}) from_node
          ON from_node.node_id = edges.from_node_id
-        AND from_node.project_id = edges.project_id
-       JOIN nodes to_node
+       JOIN ({latest_nodes}) to_node
          ON to_node.node_id = edges.to_node_id
-        AND to_node.project_id = edges.project_id
       WHERE edges.project_id = {project_id}
         AND edges.ts >= toDateTime('{start_date}')
         AND edges.ts <= toDateTime('{end_date}')
         {to_node_filter_and}{to_node_filter}
         {from_node_filter_and}{from_node_filter}
+        {edge_filter_and}{edge_filter}
    GROUP BY from_node_id,
             from_node_name,
             from_node_type,
             from_node_parent_id,
+            from_node_description,
+            from_node_class,
             to_node_id,
             to_node_name,
             to_node_type,
-            to_node_parent_id"#,
+            to_node_parent_id,
+            to_node_description,
+            to_node_class"#,
+        latest_nodes = latest_nodes(params.project_id),
         project_id = params.project_id,
         start_date = start_date_bound.format("%Y-%m-%d %H:%M:%S"),
         end_date = end_date_bound.format("%Y-%m-%d %H:%M:%S"),
@@ -186,11 +510,12 @@ pub async fn query_graph(
         to_node_filter = to_node_filter,
         from_node_filter_and = and_if_filter(&from_node_filter),
         from_node_filter = from_node_filter,
+        edge_filter_and = if edge_filter.is_empty() { "" } else { "AND " },
+        edge_filter = edge_filter,
     );
 
-    let block = client
-        .query(&format!(
-            r#"
+    let edges_query = format!(
+        r#"
                 SELECT
                     t.from_node_id as from_node_id,
                     t.from_node_name as from_node_name,
@@ -208,17 +533,32 @@ pub async fn query_graph(
                     t.edge_class as edge_class,
                     t.status_ok as status_ok,
                     t.status_expected_error as status_expected_error,
-                    t.status_unexpected_error as status_unexpected_error
+                    t.status_unexpected_error as status_unexpected_error,
+                    t.extrapolated as extrapolated
                 FROM
                     ({base_query}) AS t
-                {where_clause}
+                {where_clause}"#,
+        base_query = base_query,
+        where_clause = if !edge_post_filter.is_empty() {
+            format!("WHERE {}", edge_post_filter)
+        } else {
+            String::from("")
+        }
+    );
+
+    let block = client
+        .query(&format!(
+            r#"
+                {edges_query}
+                ORDER BY status_ok + status_expected_error + status_unexpected_error DESC
+                {limit_clause}
                 "#,
-            base_query = base_query,
-            where_clause = if !edge_post_filter.is_empty() {
-                format!("WHERE {}", edge_post_filter)
-            } else {
-                String::from("")
-            }
+            edges_query = edges_query,
+            // one more than needed tells whether anything was cut off
+            limit_clause = match limit {
+                Some(limit) => format!("LIMIT {}", limit + 1),
+                None => String::new(),
+            },
         ))
         .fetch_all()
         .await?;
@@ -226,70 +566,117 @@ pub async fn query_graph(
     let mut edges = Vec::new();
     let mut nodes = HashMap::new();
 
-    let mut node_statuses: HashMap<Uuid, (u32, u32, u32)> = HashMap::new();
-
-    for row in block.rows() {
-        let status_ok = row.get("status_ok")?;
-        let status_expected_error = row.get("status_expected_error")?;
-        let status_unexpected_error = row.get("status_unexpected_error")?;
+    let mut truncated = false;
+    for (idx, row) in block.rows().enumerate() {
+        if matches!(limit, Some(limit) if idx >= limit) {
+            truncated = true;
+            break;
+        }
         let edge = CombinedEdge {
             from_node_id: row.get("from_node_id")?,
             to_node_id: row.get("to_node_id")?,
             description: row.get("edge_description")?,
             class: row.get("edge_class")?,
-            status_ok: status_ok,
-            status_expected_error: status_expected_error,
-            status_unexpected_error: status_unexpected_error,
+            status_ok: row.get("status_ok")?,
+            status_expected_error: row.get("status_expected_error")?,
+            status_unexpected_error: row.get("status_unexpected_error")?,
+            extrapolated: row.get::<u8, _>("extrapolated")? != 0,
+            rates: Rates::default(),
         };
         edges.push(edge);
 
         let from_node = node_from_row(&row, "from_")?;
-        let from_node_id = from_node.node_id;
         nodes.insert(from_node.node_id, from_node);
         let to_node = node_from_row(&row, "to_")?;
-        let to_node_id = to_node.node_id;
-        nodes.insert(to_node_id, to_node);
-
-        let prev_to_status = node_statuses.get(&to_node_id).unwrap_or(&(0, 0, 0));
-        let to_status = (
-            prev_to_status.0 + status_ok,
-            prev_to_status.1 + status_expected_error,
-            prev_to_status.2 + status_unexpected_error,
-        );
-        node_statuses.insert(to_node_id, to_status);
-
-        let prev_from_status = node_statuses.get(&from_node_id).unwrap_or(&(0, 0, 0));
-        let from_status = (
-            prev_from_status.0 + 0,
-            prev_from_status.1 + 0,
-            prev_from_status.2 + 0,
-        );
+        nodes.insert(to_node.node_id, to_node);
+    }
 
-        node_statuses.insert(from_node_id, from_status);
+    let mut graph = Graph {
+        edges,
+        nodes: nodes
+            .into_values()
+            .map(|node| NodeWithStatus {
+                node,
+                inbound: StatusCounts::default(),
+                outbound: StatusCounts::default(),
+                extrapolated: false,
+                centrality: None,
+                rates: Rates::default(),
+            })
+            .collect(),
+        truncated,
+    };
+    graph::sum_node_statuses(&mut graph);
+    // the statuses of nodes include the calls on edges that were cut off
+    if truncated {
+        let statuses = query_node_statuses(client, &edges_query).await?;
+        for node in &mut graph.nodes {
+            if let Some(&(inbound, outbound, extrapolated)) = statuses.get(&node.node.node_id) {
+                node.inbound = inbound;
+                node.outbound = outbound;
+                node.extrapolated = extrapolated;
+            }
+        }
     }
+    if params.roll_up_transactions {
+        graph::roll_up_transactions(&mut graph);
+    }
+    add_rates(&mut graph, params);
+    Ok(graph)
+}
 
-    let mut nodes_with_status = HashMap::new();
+/// Sums up the inbound and outbound statuses of every node over the edges
+/// returned by `edges_query`.
+async fn query_node_statuses(
+    client: &mut ClientHandle,
+    edges_query: &str,
+) -> Result<HashMap<Uuid, (StatusCounts, StatusCounts, bool)>, Error> {
+    let block = client
+        .query(&format!(
+            r#"
+            SELECT
+                node_id,
+                sumIf(e.status_ok, inbound) as inbound_ok,
+                sumIf(e.status_expected_error, inbound) as inbound_expected_error,
+                sumIf(e.status_unexpected_error, inbound) as inbound_unexpected_error,
+                sumIf(e.status_ok, NOT inbound) as outbound_ok,
+                sumIf(e.status_expected_error, NOT inbound) as outbound_expected_error,
+                sumIf(e.status_unexpected_error, NOT inbound) as outbound_unexpected_error,
+                maxIf(e.extrapolated, inbound) as extrapolated
+            FROM ({edges_query}) AS e
+            ARRAY JOIN
+                [e.to_node_id, e.from_node_id] AS node_id,
+                [1, 0] AS inbound
+            GROUP BY node_id
+            "#,
+            edges_query = edges_query,
+        ))
+        .fetch_all()
+        .await?;
 
-    for (node_id, node) in nodes {
-        let status = node_statuses.get(&node_id).unwrap_or(&(0, 0, 0));
-        let node_with_status = NodeWithStatus {
-            node: node,
-            status_ok: status.0,
-            status_expected_error: status.1,
-            status_unexpected_error: status.2,
+    let mut statuses = HashMap::new();
+    for row in block.rows() {
+        let inbound = StatusCounts {
+            status_ok: row.get("inbound_ok")?,
+            status_expected_error: row.get("inbound_expected_error")?,
+            status_unexpected_error: row.get("inbound_unexpected_error")?,
         };
-        nodes_with_status.insert(node_id, node_with_status);
+        let outbound = StatusCounts {
+            status_ok: row.get("outbound_ok")?,
+            status_expected_error: row.get("outbound_expected_error")?,
+            status_unexpected_error: row.get("outbound_unexpected_error")?,
+        };
+        let extrapolated = row.get::<u8, _>("extrapolated")? != 0;
+        statuses.insert(row.get("node_id")?, (inbound, outbound, extrapolated));
     }
-
-    Ok(Graph {
-        edges,
-        nodes: nodes_with_status.into_values().collect(),
-    })
+    Ok(statuses)
 }
 
+/// Lists active nodes ordered by id, a page of `page_size` nodes at a time.
 pub async fn query_active_nodes(
     client: &mut ClientHandle,
     params: &NodeQueryParams,
+    page_size: usize,
 ) -> Result<ActiveNodes, Error> {
     let (start_date_bound, end_date_bound) = default_date_range(params);
     let edge_filter = format!(
@@ -302,6 +689,9 @@ pub async fn query_active_nodes(
     if node_filter.is_empty() {
         node_filter.push_str("1 = 1");
     }
+    if let Some(cursor) = params.cursor {
+        write!(node_filter, " AND s.node_id > toUUID('{}')", cursor)?;
+    }
 
     let block = client
         .query(&format!(
@@ -323,24 +713,28 @@ pub async fn query_active_nodes(
                     SELECT
                         from_node_id AS node_id,
                         max(ts) AS last_activity
-                    FROM edges_by_minute_mv
+                    FROM edges_by_minute
                     WHERE {edge_filter}
                     GROUP BY node_id
                     UNION ALL
                     SELECT
                         to_node_id AS node_id,
                         max(ts) AS last_activity
-                    FROM edges_by_minute_mv
+                    FROM edges_by_minute
                     WHERE {edge_filter}
                     GROUP BY node_id
                 ) s
                 GROUP BY s.node_id
             ) s
-            JOIN nodes ON s.node_id = nodes.node_id
+            JOIN ({latest_nodes}) nodes ON s.node_id = nodes.node_id
             WHERE {node_filter}
+            ORDER BY s.node_id
+            LIMIT {limit}
             "#,
+            latest_nodes = latest_nodes(params.project_id),
             edge_filter = edge_filter,
-            node_filter = node_filter
+            node_filter = node_filter,
+            limit = page_size + 1,
         ))
         .fetch_all()
         .await?;
@@ -355,71 +749,529 @@ pub async fn query_active_nodes(
         });
     }
 
-    Ok(ActiveNodes { nodes })
+    let next_cursor = if nodes.len() > page_size {
+        nodes.truncate(page_size);
+        nodes.last().map(|x| x.node.node_id)
+    } else {
+        None
+    };
+
+    Ok(ActiveNodes { nodes, next_cursor })
 }
 
-pub async fn query_histogram(
+/// Largest `ngramDistance` between a node name and the search term that still
+/// counts as a fuzzy match.
+const MAX_SEARCH_DISTANCE: f32 = 0.6;
+
+/// Finds nodes matching the search term, at most `limit` of them.
+pub async fn search_nodes(
     client: &mut ClientHandle,
-    params: &CommonQueryParams,
-) -> Result<Histogram, Error> {
+    params: &NodeSearchParams,
+    limit: usize,
+) -> Result<NodeSearchResults, Error> {
+    let q = params.q.trim();
+    if q.is_empty() {
+        return Ok(NodeSearchResults { nodes: vec![] });
+    }
     let (start_date_bound, end_date_bound) = default_date_range(params);
+    let edge_filter = format!(
+        "project_id = {} AND ts >= toDateTime('{}') AND ts <= toDateTime('{}')",
+        params.project_id,
+        start_date_bound.format("%Y-%m-%d %H:%M:%S"),
+        end_date_bound.format("%Y-%m-%d %H:%M:%S"),
+    );
+    let node_filter = get_node_filter(&params.types, "n.node_type")?;
+    let q = quote_string(&q.to_lowercase());
+
+    let block = client
+        .query(&format!(
+            r#"
+            SELECT
+                n.node_id as node_id,
+                n.node_type as node_type,
+                n.name as node_name,
+                n.parent_id as node_parent_id,
+                n.description as node_description,
+                n.class as node_class,
+                multiIf(
+                    startsWith(lowerUTF8(n.name), {q}), 0,
+                    position(lowerUTF8(n.name), {q}) > 0, 1,
+                    position(lowerUTF8(ifNull(n.description, '')), {q}) > 0, 2,
+                    3
+                ) as match_rank,
+                ngramDistanceUTF8(lowerUTF8(n.name), {q}) as distance,
+                activity.last_activity as last_activity
+            FROM ({latest_nodes}) n
+            LEFT JOIN (
+                SELECT node_id, max(last_activity) as last_activity
+                FROM (
+                    SELECT from_node_id AS node_id, max(ts) AS last_activity
+                    FROM edges_by_minute
+                    WHERE {edge_filter}
+                    GROUP BY node_id
+                    UNION ALL
+                    SELECT to_node_id AS node_id, max(ts) AS last_activity
+                    FROM edges_by_minute
+                    WHERE {edge_filter}
+                    GROUP BY node_id
+                )
+                GROUP BY node_id
+            ) activity ON activity.node_id = n.node_id
+            WHERE (match_rank < 3 OR distance <= {max_distance})
+            {node_filter_and}{node_filter}
+            ORDER BY match_rank, last_activity DESC, distance, node_name
+            LIMIT {limit}
+            "#,
+            q = q,
+            latest_nodes = latest_nodes(params.project_id),
+            edge_filter = edge_filter,
+            max_distance = MAX_SEARCH_DISTANCE,
+            node_filter_and = and_if_filter(&node_filter),
+            node_filter = node_filter,
+            limit = limit,
+        ))
+        .fetch_all()
+        .await?;
+
+    let mut nodes = Vec::new();
+    for row in block.rows() {
+        let last_activity: DateTime<Tz> = row.get("last_activity")?;
+        nodes.push(NodeSearchResult {
+            node: node_from_row(&row, "")?,
+            parent_name: None,
+            // nodes without activity get the default of the left join
+            last_activity: if last_activity.timestamp() == 0 {
+                None
+            } else {
+                Some(last_activity.with_timezone(&Utc))
+            },
+        });
+    }
 
-    let mut granularity_seconds = 60;
-    let mut duration_func = "toStartOfMinute";
+    let parent_ids: BTreeSet<Uuid> = nodes.iter().filter_map(|x| x.node.parent_id).collect();
+    if !parent_ids.is_empty() {
+        let ids: Vec<String> = parent_ids
+            .iter()
+            .map(|id| format!("toUUID('{}')", id))
+            .collect();
+        let parents: HashMap<Uuid, String> = query_nodes(
+            client,
+            params.project_id,
+            &format!("node_id IN ({})", ids.join(", ")),
+        )
+        .await?
+        .into_iter()
+        .map(|x| (x.node_id, x.name))
+        .collect();
+        for result in &mut nodes {
+            if let Some(parent_id) = result.node.parent_id {
+                result.parent_name = parents.get(&parent_id).cloned();
+            }
+        }
+    }
+
+    Ok(NodeSearchResults { nodes })
+}
+
+/// Picks minute, hour or day buckets by the length of the range, wide enough
+/// to stay within `max_buckets`.
+fn histogram_granularity(
+    start_date_bound: DateTime<Utc>,
+    end_date_bound: DateTime<Utc>,
+    tz: Tz,
+    max_buckets: usize,
+) -> Granularity {
     let duration = end_date_bound.signed_duration_since(start_date_bound);
-    if duration > Duration::days(14) {
-        duration_func = "toStartOfDay";
-        granularity_seconds = 60 * 60 * 24;
+    let unit = if duration > Duration::days(14) {
+        GranularityUnit::Day
     } else if duration > Duration::hours(24) {
-        duration_func = "toStartOfHour";
-        granularity_seconds = 60 * 60;
+        GranularityUnit::Hour
+    } else {
+        GranularityUnit::Minute
+    };
+    let buckets =
+        Granularity { amount: 1, unit }.bucket_count(start_date_bound, end_date_bound, tz);
+    let max_buckets = max_buckets.max(1) as i64;
+    let mut granularity = Granularity {
+        amount: ((buckets + max_buckets - 1) / max_buckets).max(1) as u32,
+        unit,
+    };
+    // unaligned ends can add a bucket
+    while granularity.bucket_count(start_date_bound, end_date_bound, tz) > max_buckets {
+        granularity.amount += 1;
+    }
+    granularity
+}
+
+fn get_histogram_filter(params: &HistogramQueryParams) -> Result<String, Error> {
+    let mut clauses = Vec::new();
+    if !params.node_ids.is_empty() {
+        let ids: Vec<String> = params
+            .node_ids
+            .iter()
+            .map(|id| format!("toUUID('{}')", id))
+            .collect();
+        clauses.push(format!("to_node_id IN ({})", ids.join(", ")));
     }
+    if let Some(from_node_id) = params.from_node_id {
+        clauses.push(format!("from_node_id = toUUID('{}')", from_node_id));
+    }
+    if let Some(to_node_id) = params.to_node_id {
+        clauses.push(format!("to_node_id = toUUID('{}')", to_node_id));
+    }
+    let type_filter = get_node_filter(&params.node_types, "node_type")?;
+    if !type_filter.is_empty() {
+        clauses.push(format!(
+            "to_node_id IN (SELECT node_id FROM nodes WHERE project_id = {} AND {})",
+            params.project_id, type_filter
+        ));
+    }
+    Ok(clauses.join(" AND "))
+}
+
+pub async fn query_histogram(
+    client: &mut ClientHandle,
+    params: &HistogramQueryParams,
+    max_buckets: usize,
+) -> Result<Histogram, Error> {
+    let (start_date_bound, end_date_bound) = default_date_range(params);
+    let tz = params.timezone.unwrap_or(Tz::UTC);
+    let granularity = match params.granularity {
+        Some(granularity) => {
+            let buckets = granularity.bucket_count(start_date_bound, end_date_bound, tz);
+            if buckets > max_buckets as i64 {
+                return Err(anyhow::anyhow!(
+                    "granularity {} makes {} buckets, at most {} are allowed",
+                    granularity,
+                    buckets,
+                    max_buckets
+                ));
+            }
+            granularity
+        }
+        None => histogram_granularity(start_date_bound, end_date_bound, tz, max_buckets),
+    };
+    let filter = get_histogram_filter(params)?;
+    // edges_by_minute cannot be split up, sub-minute buckets are counted from
+    // the raw edges, upscaled like the view does
+    let (counts, table) = if granularity.unit == GranularityUnit::Second {
+        (
+            r#"
+                sumIf(toUInt32(least(round(n / sample_rate), 4294967295)), status = 1) as status_ok,
+                sumIf(toUInt32(least(round(n / sample_rate), 4294967295)), status = 2) as status_expected_error,
+                sumIf(toUInt32(least(round(n / sample_rate), 4294967295)), status = 3) as status_unexpected_error,
+                max(toUInt8(sample_rate < 1)) as extrapolated"#,
+            r#"(
+                SELECT project_id, ts, from_node_id, to_node_id, status, n, sample_rate
+                FROM edges
+                UNION ALL
+                SELECT project_id, ts, from_node_id, to_node_id, status, n, sample_rate
+                FROM edges_backfill
+            )"#,
+        )
+    } else {
+        (
+            r#"
+                sumIfMerge(status_ok) as status_ok,
+                sumIfMerge(status_expected_error) as status_expected_error,
+                sumIfMerge(status_unexpected_error) as status_unexpected_error,
+                max(extrapolated) as extrapolated"#,
+            "edges_by_minute",
+        )
+    };
 
     let block = client
         .query(&format!(
             r#"
             SELECT
-                {duration_func}(ts) as ts,
-                plus(plus(sumIfMerge(status_ok), sumIfMerge(status_expected_error)), sumIfMerge(status_unexpected_error)) as count
-            FROM edges_by_minute
+                toStartOfInterval(ts, INTERVAL {amount} {unit}, '{timezone}') as ts,{counts}
+            FROM {table}
             WHERE project_id = {project_id}
             AND ts >= toDateTime('{start_date}')
             AND ts <= toDateTime('{end_date}')
+            {filter_and}{filter}
             GROUP BY ts
             ORDER BY ts
             "#,
-            duration_func = duration_func,
+            amount = granularity.amount,
+            counts = counts,
+            table = table,
+            unit = match granularity.unit {
+                GranularityUnit::Second => "second",
+                GranularityUnit::Minute => "minute",
+                GranularityUnit::Hour => "hour",
+                GranularityUnit::Day => "day",
+            },
+            timezone = tz.name(),
             project_id = params.project_id,
             start_date = start_date_bound.format("%Y-%m-%d %H:%M:%S"),
             end_date = end_date_bound.format("%Y-%m-%d %H:%M:%S"),
+            filter_and = and_if_filter(&filter),
+            filter = filter,
         ))
         .fetch_all()
         .await?;
 
-    let mut buckets = Vec::new();
+    let mut buckets = BTreeMap::new();
 
     for row in block.rows() {
         let ts: DateTime<Tz> = row.get("ts")?;
-        buckets.push(Bucket {
-            ts: ts.with_timezone(&Utc),
-            n: row.get("count")?,
+        let status_ok: u64 = row.get("status_ok")?;
+        let status_expected_error: u64 = row.get("status_expected_error")?;
+        let status_unexpected_error: u64 = row.get("status_unexpected_error")?;
+        let ts = ts.with_timezone(&Utc);
+        buckets.insert(
+            ts,
+            Bucket {
+                ts,
+                n: status_ok + status_expected_error + status_unexpected_error,
+                status_ok,
+                status_expected_error,
+                status_unexpected_error,
+                extrapolated: row.get::<u8, _>("extrapolated")? != 0,
+            },
+        );
+    }
+
+    // a quiet bucket is one without calls rather than a gap in the chart
+    for ts in granularity.buckets(start_date_bound, end_date_bound, tz) {
+        buckets.entry(ts).or_insert(Bucket {
+            ts,
+            n: 0,
+            status_ok: 0,
+            status_expected_error: 0,
+            status_unexpected_error: 0,
+            extrapolated: false,
         });
     }
 
     Ok(Histogram {
-        buckets: buckets,
-        granularity_seconds: granularity_seconds,
+        buckets: buckets.into_values().collect(),
+        granularity_seconds: granularity.seconds(),
     })
 }
 
+async fn query_nodes(
+    client: &mut ClientHandle,
+    project_id: u64,
+    filter: &str,
+) -> Result<Vec<Node>, Error> {
+    let block = client
+        .query(&format!(
+            r#"
+            SELECT
+                node_id,
+                argMax(node_type, ts) as node_type,
+                argMax(name, ts) as node_name,
+                argMax(parent_id, ts) as node_parent_id,
+                argMax(description, ts) as node_description,
+                argMax(class, ts) as node_class
+            FROM nodes
+            WHERE project_id = {project_id}
+              AND {filter}
+            GROUP BY node_id
+            "#,
+            project_id = project_id,
+            filter = filter,
+        ))
+        .fetch_all()
+        .await?;
+
+    let mut nodes = Vec::new();
+    for row in block.rows() {
+        nodes.push(node_from_row(&row, "")?);
+    }
+    Ok(nodes)
+}
+
+pub async fn query_node_detail(
+    client: &mut ClientHandle,
+    node_id: Uuid,
+    params: &CommonQueryParams,
+    max_histogram_buckets: usize,
+) -> Result<Option<NodeDetail>, Error> {
+    let node = match query_nodes(
+        client,
+        params.project_id,
+        &format!("node_id = toUUID('{}')", node_id),
+    )
+    .await?
+    .pop()
+    {
+        Some(node) => node,
+        None => return Ok(None),
+    };
+
+    let parent = match node.parent_id {
+        Some(parent_id) => query_nodes(
+            client,
+            params.project_id,
+            &format!("node_id = toUUID('{}')", parent_id),
+        )
+        .await?
+        .pop(),
+        None => None,
+    };
+    let children = query_nodes(
+        client,
+        params.project_id,
+        &format!("parent_id = toUUID('{}')", node_id),
+    )
+    .await?;
+
+    let graph = query_graph_filtered(
+        client,
+        &GraphQueryParams {
+            common: params.clone(),
+            ..Default::default()
+        },
+        &format!(
+            "(edges.from_node_id = toUUID('{id}') OR edges.to_node_id = toUUID('{id}'))",
+            id = node_id
+        ),
+        None,
+    )
+    .await?;
+
+    let neighbors: HashMap<Uuid, Node> = graph
+        .nodes
+        .into_iter()
+        .map(|x| (x.node.node_id, x.node))
+        .collect();
+    let mut node_status = NodeWithStatus {
+        node,
+        inbound: StatusCounts::default(),
+        outbound: StatusCounts::default(),
+        extrapolated: false,
+        centrality: None,
+        rates: Rates::default(),
+    };
+    let mut callers = Vec::new();
+    let mut callees = Vec::new();
+    for edge in graph.edges {
+        if edge.to_node_id == node_id {
+            node_status.inbound.add_edge(&edge);
+            node_status.extrapolated |= edge.extrapolated;
+            if let Some(caller) = neighbors.get(&edge.from_node_id) {
+                callers.push(NodeDependency {
+                    node: caller.clone(),
+                    edge: edge.clone(),
+                });
+            }
+        }
+        if edge.from_node_id == node_id {
+            node_status.outbound.add_edge(&edge);
+            if let Some(callee) = neighbors.get(&edge.to_node_id) {
+                callees.push(NodeDependency {
+                    node: callee.clone(),
+                    edge,
+                });
+            }
+        }
+    }
+    node_status.rates = Rates::new(
+        node_status.inbound.status_ok,
+        node_status.inbound.status_expected_error,
+        node_status.inbound.status_unexpected_error,
+        window_minutes(params),
+    );
+
+    let series = query_histogram(
+        client,
+        &HistogramQueryParams {
+            common: params.clone(),
+            to_node_id: Some(node_id),
+            ..Default::default()
+        },
+        max_histogram_buckets,
+    )
+    .await?;
+
+    let (start_date_bound, end_date_bound) = default_date_range(params);
+    let block = client
+        .query(&format!(
+            r#"
+            SELECT
+                max(ts) as last_activity,
+                count() as edge_count
+            FROM edges_by_minute
+            WHERE project_id = {project_id}
+            AND (from_node_id = toUUID('{node_id}') OR to_node_id = toUUID('{node_id}'))
+            AND ts >= toDateTime('{start_date}')
+            AND ts <= toDateTime('{end_date}')
+            "#,
+            project_id = params.project_id,
+            node_id = node_id,
+            start_date = start_date_bound.format("%Y-%m-%d %H:%M:%S"),
+            end_date = end_date_bound.format("%Y-%m-%d %H:%M:%S"),
+        ))
+        .fetch_all()
+        .await?;
+
+    let mut last_activity = None;
+    for row in block.rows() {
+        let edge_count: u64 = row.get("edge_count")?;
+        if edge_count > 0 {
+            let ts: DateTime<Tz> = row.get("last_activity")?;
+            last_activity = Some(ts.with_timezone(&Utc));
+        }
+    }
+
+    Ok(Some(NodeDetail {
+        node: node_status,
+        parent,
+        children,
+        callers,
+        callees,
+        last_activity,
+        series,
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dates::DateSpec;
     use crate::payloads::{CommonQueryParams, EdgeStatus, NodeType};
     use chrono::{DateTime, NaiveDateTime, Utc};
     use rand::prelude::*;
     use uuid::Uuid;
 
+    #[test]
+    fn test_like_pattern() {
+        assert_eq!(like_pattern("check"), "check%");
+        assert_eq!(like_pattern("*out"), "%out");
+        assert_eq!(like_pattern("a_b?"), "a\\_b_");
+        assert_eq!(quote_string("it's \\"), "'it\\'s \\\\'");
+    }
+
+    #[test]
+    fn test_attribute_filter() {
+        let filter = AttributeFilter {
+            node_class: Some("db".into()),
+            edge_class: Some("http".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_attribute_filter(&filter),
+            "(t.from_node_class = 'db' OR t.to_node_class = 'db') AND t.edge_class = 'http'"
+        );
+    }
+
+    #[test]
+    fn test_histogram_granularity() {
+        // an hour starting mid-minute touches 61 minutes
+        let start = "2021-06-09T10:00:30Z".parse().unwrap();
+        let end = "2021-06-09T11:00:00Z".parse().unwrap();
+        let granularity = histogram_granularity(start, end, Tz::UTC, 60);
+        assert_eq!(granularity.to_string(), "2m");
+        assert!(granularity.bucket_count(start, end, Tz::UTC) <= 60);
+        assert_eq!(
+            histogram_granularity(start, end, Tz::UTC, 61).to_string(),
+            "1m"
+        );
+    }
+
     fn create_nodes() -> Vec<Node> {
         let mut parents = vec![];
         let mut children = vec![];
@@ -569,6 +1421,7 @@ mod tests {
                 description: Some("calls".into()),
                 class: None,
                 n: count,
+                sample_rate: 1.0,
             });
 
             // if it's a transaction -> transaction then the src transaction
@@ -583,6 +1436,7 @@ mod tests {
                     description: Some("calls".into()),
                     class: None,
                     n: count,
+                    sample_rate: 1.0,
                 });
             }
         }
@@ -628,8 +1482,9 @@ mod tests {
             &GraphQueryParams {
                 common: CommonQueryParams {
                     project_id: 1,
-                    start_date: Some(Utc::now() - Duration::weeks(20)),
-                    end_date: Some(Utc::now() - Duration::weeks(19)),
+                    start_date: Some(DateSpec::Ago(Duration::weeks(20))),
+                    end_date: Some(DateSpec::Ago(Duration::weeks(19))),
+                    ..Default::default()
                 },
                 ..Default::default()
             },
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+use crate::payloads::SubmitResponse;
+
+/// Configuration of submission deduplication (read from `Rocket.toml`).
+#[derive(Deserialize, Debug, Clone)]
+pub struct DedupConfig {
+    /// How long a batch id is remembered, in seconds.
+    #[serde(default = "default_dedup_window")]
+    pub dedup_window: u64,
+    /// How many batch ids are remembered per project.
+    #[serde(default = "default_dedup_capacity")]
+    pub dedup_capacity: usize,
+}
+
+fn default_dedup_window() -> u64 {
+    15 * 60
+}
+
+fn default_dedup_capacity() -> usize {
+    10_000
+}
+
+enum BatchState {
+    Pending,
+    Done(SubmitResponse),
+}
+
+/// What `BatchLog::begin` knows about a batch.
+#[derive(Debug)]
+pub enum BatchStatus {
+    /// The batch was not seen before and is now in flight.
+    New,
+    /// The same batch is still being written.
+    InFlight,
+    /// Every remembered batch is still being written, there is no room to
+    /// track another one.
+    Full,
+    /// The batch was already accepted, with its original acknowledgment.
+    Accepted(SubmitResponse),
+}
+
+struct BatchEntry {
+    seen: Instant,
+    state: BatchState,
+}
+
+#[derive(Default)]
+struct ProjectBatches {
+    order: VecDeque<(Instant, String)>,
+    entries: HashMap<String, BatchEntry>,
+}
+
+impl ProjectBatches {
+    /// Forgets expired batches and makes room for one more.  Batches still
+    /// being written are kept, otherwise a retry would be stored twice.
+    fn evict(&mut self, now: Instant, window: Duration, capacity: usize) {
+        let mut in_flight = Vec::new();
+        while let Some((seen, _)) = self.order.front() {
+            if self.order.len() + in_flight.len() < capacity && now.duration_since(*seen) < window {
+                break;
+            }
+            let (seen, batch_id) = self.order.pop_front().unwrap();
+            // only drop the entry if it was not re-registered in the meantime
+            match self.entries.get(&batch_id) {
+                Some(BatchEntry {
+                    seen: entry_seen,
+                    state: BatchState::Pending,
+                }) if *entry_seen == seen => in_flight.push((seen, batch_id)),
+                Some(entry) if entry.seen == seen => {
+                    self.entries.remove(&batch_id);
+                }
+                _ => {}
+            }
+        }
+        for entry in in_flight.into_iter().rev() {
+            self.order.push_front(entry);
+        }
+    }
+}
+
+/// Remembers recently submitted batch ids per project.
+pub struct BatchLog {
+    window: Duration,
+    capacity: usize,
+    projects: Mutex<HashMap<u64, ProjectBatches>>,
+}
+
+impl BatchLog {
+    pub fn new(config: &DedupConfig) -> BatchLog {
+        BatchLog {
+            window: Duration::from_secs(config.dedup_window),
+            capacity: config.dedup_capacity.max(1),
+            projects: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Marks a batch as in flight unless it is already known.
+    pub fn begin(&self, project_id: u64, batch_id: &str) -> BatchStatus {
+        let now = Instant::now();
+        let mut projects = self.projects.lock().unwrap();
+        let batches = projects.entry(project_id).or_default();
+        batches.evict(now, self.window, self.capacity);
+
+        match batches.entries.get(batch_id) {
+            Some(BatchEntry {
+                state: BatchState::Done(ack),
+                ..
+            }) => {
+                let mut ack = ack.clone();
+                ack.duplicate = true;
+                BatchStatus::Accepted(ack)
+            }
+            Some(BatchEntry {
+                state: BatchState::Pending,
+                ..
+            }) => BatchStatus::InFlight,
+            None if batches.order.len() >= self.capacity => BatchStatus::Full,
+            None => {
+                batches.order.push_back((now, batch_id.to_string()));
+                batches.entries.insert(
+                    batch_id.to_string(),
+                    BatchEntry {
+                        seen: now,
+                        state: BatchState::Pending,
+                    },
+                );
+                BatchStatus::New
+            }
+        }
+    }
+
+    /// Records the acknowledgment of a batch that was accepted.
+    pub fn finish(&self, project_id: u64, batch_id: &str, ack: &SubmitResponse) {
+        let mut projects = self.projects.lock().unwrap();
+        if let Some(entry) = projects
+            .get_mut(&project_id)
+            .and_then(|x| x.entries.get_mut(batch_id))
+        {
+            entry.state = BatchState::Done(ack.clone());
+        }
+    }
+
+    /// Forgets a batch that failed so that a retry is processed again.
+    pub fn abort(&self, project_id: u64, batch_id: &str) {
+        let mut projects = self.projects.lock().unwrap();
+        if let Some(batches) = projects.get_mut(&project_id) {
+            batches.entries.remove(batch_id);
+            batches.order.retain(|(_, x)| x != batch_id);
+        }
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Batch deduplication", |rocket| async {
+        match rocket.figment().extract::<DedupConfig>() {
+            Ok(config) => Ok(rocket.manage(BatchLog::new(&config))),
+            Err(err) => {
+                error!("invalid dedup config: {}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(edges: usize) -> SubmitResponse {
+        SubmitResponse {
+            edges,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_duplicate_batches() {
+        let log = BatchLog::new(&DedupConfig {
+            dedup_window: 60,
+            dedup_capacity: 2,
+        });
+
+        assert!(matches!(log.begin(1, "a"), BatchStatus::New));
+        assert!(matches!(log.begin(1, "a"), BatchStatus::InFlight));
+        log.finish(1, "a", &ack(3));
+
+        let original = match log.begin(1, "a") {
+            BatchStatus::Accepted(ack) => ack,
+            status => panic!("unexpected {:?}", status),
+        };
+        assert!(original.duplicate);
+        assert_eq!(original.edges, 3);
+
+        // batch ids are scoped to the project
+        assert!(matches!(log.begin(2, "a"), BatchStatus::New));
+
+        // failed batches can be retried and do not take up capacity
+        assert!(matches!(log.begin(1, "b"), BatchStatus::New));
+        log.abort(1, "b");
+        assert_eq!(log.projects.lock().unwrap()[&1].order.len(), 1);
+        assert!(matches!(log.begin(1, "b"), BatchStatus::New));
+        log.finish(1, "b", &ack(1));
+
+        // the oldest batch is forgotten once the capacity is exceeded
+        assert!(matches!(log.begin(1, "c"), BatchStatus::New));
+        log.finish(1, "c", &ack(1));
+        assert!(matches!(log.begin(1, "a"), BatchStatus::New));
+
+        // batches being written are never forgotten
+        assert!(matches!(log.begin(1, "d"), BatchStatus::New));
+        assert!(matches!(log.begin(1, "e"), BatchStatus::Full));
+        assert!(matches!(log.begin(1, "a"), BatchStatus::InFlight));
+        assert!(matches!(log.begin(1, "d"), BatchStatus::InFlight));
+        log.finish(1, "a", &ack(1));
+        assert!(matches!(log.begin(1, "e"), BatchStatus::New));
+        assert!(matches!(log.begin(1, "d"), BatchStatus::InFlight));
+    }
+}
@@ -1,218 +1,416 @@
-use std::cmp;
-use std::collections::BTreeSet;
-
+use chrono::Utc;
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::futures::Stream;
+use rocket::http::Status;
+use rocket::response::stream::TextStream;
 use rocket::serde::json::Json;
-use serde::{Deserialize, Serialize};
+use rocket::State;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use uuid::Uuid;
 
+use crate::codec::SubmitBody;
+use crate::db::register_submission;
 use crate::db::{self, get_client};
-use crate::db::{register_edges, register_nodes};
-use crate::error::ApiError;
+use crate::dedup::{BatchLog, BatchStatus};
+use crate::error::{ApiError, Error};
+use crate::graph;
+use crate::import::{Importer, DEFAULT_BATCH_SIZE};
+use crate::limits::ResponseLimits;
 use crate::payloads::{
-    ActiveNodes, CombinedEdge, CommonQueryParams, Edge, Graph, GraphQueryParams, Histogram, Node,
-    NodeQueryParams, ServiceMap, ServiceMapQueryParams,
+    ActiveNodes, BlastRadius, CommonQueryParams, CycleQueryParams, Cycles, Graph, GraphDiff,
+    GraphDiffQueryParams, GraphQueryParams, Health, Histogram, HistogramQueryParams,
+    ImpactQueryParams, ImportReport, NeighborhoodQueryParams, NodeDetail, NodeQueryParams,
+    NodeSearchParams, NodeSearchResults, PathQueryParams, Paths, RootCauses, ServiceMap,
+    ServiceMapQueryParams, StoredSubmission, SubmitData, SubmitResponse,
 };
-
-#[derive(Serialize, Deserialize)]
-pub struct SubmitData {
-    project_id: u64,
-    nodes: Vec<Node>,
-    edges: Vec<Edge>,
-}
+use crate::query::QueryParams;
+use crate::spool::{self, Spool};
+use crate::timestamps::TimestampConfig;
 
 #[get("/health")]
-pub fn health() -> String {
-    return String::from("OK");
+pub fn health(spool: &State<Spool>) -> Json<Health> {
+    let spool = spool.stats();
+    Json(Health {
+        status: if spool.depth > 0 { "degraded" } else { "ok" }.into(),
+        spool,
+    })
 }
 
-#[post("/submit", format = "json", data = "<data>")]
-pub async fn submit(data: Json<SubmitData>) -> Result<String, ApiError> {
+async fn write_submission(data: &StoredSubmission) -> Result<(), Error> {
     let mut client = get_client().await?;
-    if !data.nodes.is_empty() {
-        register_nodes(&mut client, data.project_id, &data.nodes).await?;
+    register_submission(&mut client, data).await
+}
+
+async fn store_submission(
+    data: SubmitData,
+    spool: &Spool,
+    timestamps: &TimestampConfig,
+) -> Result<SubmitResponse, Error> {
+    let (data, decisions) = timestamps.apply(Utc::now(), data);
+    let mut ack = SubmitResponse {
+        batch_id: data.data.batch_id.clone(),
+        nodes: data.data.nodes.len(),
+        // only the edges that are stored, after the timestamp policy
+        edges: data.data.edges.len() + data.backfill_edges.len(),
+        timestamps: decisions,
+        ..Default::default()
+    };
+
+    // while there is a backlog everything goes through the spool so that
+    // submissions are written in the order they came in.
+    if !spool.is_empty() {
+        spool.push(&data).await?;
+        ack.spooled = true;
+        return Ok(ack);
+    }
+    match write_submission(&data).await {
+        Ok(()) => {}
+        Err(err) if spool::is_unavailable(&err) => {
+            spool.push(&data).await?;
+            ack.spooled = true;
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(ack)
+}
+
+#[post("/submit", data = "<body>")]
+pub async fn submit(
+    body: SubmitBody,
+    spool: &State<Spool>,
+    batches: &State<BatchLog>,
+    timestamps: &State<TimestampConfig>,
+) -> Result<Json<SubmitResponse>, ApiError> {
+    let SubmitBody(data) = body;
+    data.validate()?;
+
+    let (project_id, batch_id) = match data.batch_id {
+        Some(ref batch_id) => (data.project_id, batch_id.clone()),
+        None => return Ok(Json(store_submission(data, spool, timestamps).await?)),
+    };
+
+    match batches.begin(project_id, &batch_id) {
+        BatchStatus::New => {}
+        BatchStatus::Accepted(ack) => return Ok(Json(ack)),
+        // the client retried too early, it can try again once this one is
+        // written or failed
+        BatchStatus::InFlight => {
+            return Err(ApiError::with_status(
+                Status::Conflict,
+                anyhow::anyhow!("batch {} is already being processed", batch_id),
+            ))
+        }
+        BatchStatus::Full => {
+            return Err(ApiError::with_status(
+                Status::ServiceUnavailable,
+                anyhow::anyhow!("too many batches are being processed"),
+            ))
+        }
     }
-    if !data.edges.is_empty() {
-        register_edges(&mut client, data.project_id, &data.edges).await?;
+    match store_submission(data, spool, timestamps).await {
+        Ok(ack) => {
+            batches.finish(project_id, &batch_id, &ack);
+            Ok(Json(ack))
+        }
+        Err(err) => {
+            batches.abort(project_id, &batch_id);
+            Err(err.into())
+        }
+    }
+}
+
+fn report_line(report: &ImportReport) -> String {
+    format!("{}\n", serde_json::to_string(report).unwrap_or_default())
+}
+
+/// Streams one JSON line with the counts after every batch and the full
+/// report as the last line.
+#[post("/import?<project_id>&<batch_size>", data = "<data>")]
+pub fn import<'r>(
+    data: Data<'r>,
+    project_id: Option<u64>,
+    batch_size: Option<usize>,
+    limits: &'r Limits,
+    timestamps: &'r State<TimestampConfig>,
+) -> TextStream<impl Stream<Item = String> + 'r> {
+    let limit = limits.get("import").unwrap_or_else(|| 1.gibibytes());
+    let mut importer = Importer::new(
+        project_id,
+        batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+        timestamps.inner().clone(),
+    );
+    TextStream! {
+        let mut lines = BufReader::new(data.open(limit)).lines();
+        let mut last_batches = 0;
+        let result = match get_client().await {
+            Ok(mut client) => loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break importer.flush(&mut client).await,
+                    Err(err) => break Err(err.into()),
+                };
+                if let Err(err) = importer.feed_line(&mut client, &line).await {
+                    break Err(err);
+                }
+                if importer.report().batches != last_batches {
+                    last_batches = importer.report().batches;
+                    yield report_line(&importer.report().progress());
+                }
+            },
+            Err(err) => Err(err),
+        };
+        yield report_line(&importer.finish(result));
     }
-    Ok("".into())
 }
 
 #[post("/graph", format = "json", data = "<params>")]
-pub async fn query_graph(params: Json<GraphQueryParams>) -> Result<Json<Graph>, ApiError> {
+pub async fn query_graph(
+    params: Json<GraphQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<Graph>, ApiError> {
     let mut client = get_client().await?;
-    Ok(Json(db::query_graph(&mut client, &params).await?))
+    Ok(Json(
+        db::query_graph_limited(&mut client, &params, limits).await?,
+    ))
+}
+
+#[get("/graph")]
+pub async fn get_graph(
+    params: QueryParams<GraphQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<Graph>, ApiError> {
+    query_graph(Json(params.0), limits).await
 }
 
 #[post("/active-nodes", format = "json", data = "<params>")]
 pub async fn query_active_nodes(
     params: Json<NodeQueryParams>,
+    limits: &State<ResponseLimits>,
 ) -> Result<Json<ActiveNodes>, ApiError> {
     let mut client = get_client().await?;
-    Ok(Json(db::query_active_nodes(&mut client, &params).await?))
+    let page_size = limits.page_size(params.limit);
+    Ok(Json(
+        db::query_active_nodes(&mut client, &params, page_size).await?,
+    ))
+}
+
+#[get("/active-nodes")]
+pub async fn get_active_nodes(
+    params: QueryParams<NodeQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<ActiveNodes>, ApiError> {
+    query_active_nodes(Json(params.0), limits).await
 }
 
 #[post("/service-map", format = "json", data = "<params>")]
 pub async fn query_service_map(
     params: Json<ServiceMapQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<ServiceMap>, ApiError> {
+    Ok(Json(build_service_map(params.into_inner(), limits).await?))
+}
+
+#[get("/service-map")]
+pub async fn get_service_map(
+    params: QueryParams<ServiceMapQueryParams>,
+    limits: &State<ResponseLimits>,
 ) -> Result<Json<ServiceMap>, ApiError> {
+    Ok(Json(build_service_map(params.0, limits).await?))
+}
+
+async fn build_service_map(
+    params: ServiceMapQueryParams,
+    limits: &ResponseLimits,
+) -> Result<ServiceMap, Error> {
     let mut client = get_client().await?;
 
-    let graph = db::query_graph(&mut client, &params.clone().into()).await?;
-    let active_nodes = db::query_active_nodes(&mut client, &params.clone().into()).await?;
-
-    // let edges: Vec<CombinedEdge> = graph
-    //     .edges
-    //     .clone()
-    //     .into_iter()
-    //     .filter(|edge| {
-    //         let source_node = graph
-    //             .nodes
-    //             .iter()
-    //             .find(|node| node.node.node_id == edge.from_node_id);
-    //         let target_node = graph
-    //             .nodes
-    //             .iter()
-    //             .find(|node| node.node.node_id == edge.to_node_id);
-    //         if let Some(source_node) = source_node {
-    //             if let Some(target_node) = target_node {
-    //                 let params_service_to_service = params.from_types.len() == 1
-    //                     && params.from_types.contains(&NodeType::Service)
-    //                     && params.to_types.len() == 1
-    //                     && params.to_types.contains(&NodeType::Service);
-
-    //                 let service_to_service = source_node.node.node_type == NodeType::Service
-    //                     && target_node.node.node_type == NodeType::Service;
-    //                 let service_to_transaction = source_node.node.node_type == NodeType::Service
-    //                     && target_node.node.node_type == NodeType::Transaction;
-
-    //                 let transaction_to_service = source_node.node.node_type
-    //                     == NodeType::Transaction
-    //                     && target_node.node.node_type == NodeType::Service;
-
-    //                 if params_service_to_service && service_to_service {
-    //                     return true;
-    //                 }
-
-    //                 if service_to_service || service_to_transaction {
-    //                     return false;
-    //                 }
-
-    //                 if transaction_to_service {
-    //                     let has_child = graph
-    //                         .nodes
-    //                         .iter()
-    //                         .find(|node| {
-    //                             if let Some(parent_id) = node.node.parent_id {
-    //                                 return parent_id == target_node.node.node_id;
-    //                             }
-    //                             return false;
-    //                         })
-    //                         .is_some();
-    //                     if has_child {
-    //                         return false;
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //         return true;
-    //     })
-    //     .collect();
-
-    // let keep_nodes: BTreeSet<Uuid> =
-    //     edges
-    //         .iter()
-    //         .fold(BTreeSet::new(), |mut acc: BTreeSet<Uuid>, edge| {
-    //             acc.insert(edge.from_node_id);
-    //             acc.insert(edge.to_node_id);
-    //             return acc;
-    //         });
-
-    // let nodes = graph
-    //     .nodes
-    //     .into_iter()
-    //     .filter(|node| {
-    //         return keep_nodes.contains(&node.node.node_id);
-    //     })
-    //     .collect();
-
-    // let graph = Graph {
-    //     edges,
-    //     nodes: graph.nodes,
-    // };
-
-    if let Some(volume_filter) = params.traffic_volume {
-        let mut volume_filter = cmp::min(volume_filter, 100);
-        volume_filter = cmp::max(volume_filter, 0);
-
-        if graph.edges.len() != 0 && volume_filter > 0 {
-            let volume_filter = volume_filter as f64;
-            let volumes: Vec<u32> = graph
-                .edges
-                .iter()
-                .map(|edge| {
-                    return edge.status_expected_error
-                        + edge.status_ok
-                        + edge.status_unexpected_error;
-                })
-                .collect();
-
-            let min_volume = volumes.iter().min().unwrap();
-            let max_volume = volumes.iter().max().unwrap();
-
-            let mut keep_nodes: BTreeSet<Uuid> = BTreeSet::new();
-
-            let edges: Vec<CombinedEdge> = graph
-                .edges
-                .into_iter()
-                .filter(|edge| {
-                    let edge_volume =
-                        edge.status_expected_error + edge.status_ok + edge.status_unexpected_error;
-
-                    if (max_volume - min_volume) == 0 {
-                        return false;
-                    }
-
-                    let percentage =
-                        (edge_volume - min_volume) as f64 / (max_volume - min_volume) as f64;
-                    let percentage = percentage * 100.0;
-
-                    let result = percentage >= volume_filter;
-
-                    if result {
-                        keep_nodes.insert(edge.from_node_id);
-                        keep_nodes.insert(edge.to_node_id);
-                    }
-
-                    return result;
-                })
-                .collect();
-
-            let nodes = graph
-                .nodes
-                .into_iter()
-                .filter(|node| {
-                    return keep_nodes.contains(&node.node.node_id);
-                })
-                .collect();
-
-            let graph = Graph { edges, nodes };
-
-            return Ok(Json(ServiceMap {
-                graph,
-                active_nodes,
-            }));
-        }
+    let mut graph = db::query_graph(&mut client, &params.clone().into()).await?;
+    let active_nodes =
+        db::query_active_nodes(&mut client, &params.clone().into(), limits.max_graph_nodes).await?;
+
+    if let Some(view) = params.view {
+        db::add_missing_parents(&mut client, params.project_id, &mut graph).await?;
+        graph = graph::apply_view(graph, view);
+    }
+    if params.roll_up_transactions {
+        graph::roll_up_transactions(&mut graph);
+    }
+    if params.view.is_some() || params.roll_up_transactions {
+        db::add_rates(&mut graph, &params);
     }
 
-    Ok(Json(ServiceMap {
+    let mut graph =
+        db::filter_traffic(graph, &params, &params.traffic, params.roll_up_transactions);
+    if params.with_centrality {
+        graph = db::add_centrality(graph, limits).await?;
+    }
+    let graph = graph::truncate(graph, limits.max_graph_nodes, limits.max_graph_edges);
+
+    Ok(ServiceMap {
+        truncated: graph.truncated || active_nodes.next_cursor.is_some(),
         graph,
         active_nodes,
-    }))
+    })
+}
+
+#[post("/neighborhood", format = "json", data = "<params>")]
+pub async fn query_neighborhood(
+    params: Json<NeighborhoodQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<Graph>, ApiError> {
+    let mut client = get_client().await?;
+    let graph = db::query_neighborhood(&mut client, &params).await?;
+
+    Ok(Json(graph::truncate(
+        graph,
+        limits.max_graph_nodes,
+        limits.max_graph_edges,
+    )))
+}
+
+#[get("/neighborhood")]
+pub async fn get_neighborhood(
+    params: QueryParams<NeighborhoodQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<Graph>, ApiError> {
+    query_neighborhood(Json(params.0), limits).await
+}
+
+#[post("/paths", format = "json", data = "<params>")]
+pub async fn query_paths(params: Json<PathQueryParams>) -> Result<Json<Paths>, ApiError> {
+    let mut client = get_client().await?;
+
+    Ok(Json(db::query_paths(&mut client, &params).await?))
+}
+
+#[get("/paths")]
+pub async fn get_paths(params: QueryParams<PathQueryParams>) -> Result<Json<Paths>, ApiError> {
+    query_paths(Json(params.0)).await
+}
+
+#[post("/blast-radius", format = "json", data = "<params>")]
+pub async fn query_blast_radius(
+    params: Json<ImpactQueryParams>,
+) -> Result<Json<BlastRadius>, ApiError> {
+    let mut client = get_client().await?;
+
+    Ok(Json(db::query_blast_radius(&mut client, &params).await?))
+}
+
+#[get("/blast-radius")]
+pub async fn get_blast_radius(
+    params: QueryParams<ImpactQueryParams>,
+) -> Result<Json<BlastRadius>, ApiError> {
+    query_blast_radius(Json(params.0)).await
+}
+
+#[post("/cycles", format = "json", data = "<params>")]
+pub async fn query_cycles(params: Json<CycleQueryParams>) -> Result<Json<Cycles>, ApiError> {
+    let mut client = get_client().await?;
+
+    Ok(Json(db::query_cycles(&mut client, &params).await?))
+}
+
+#[get("/cycles")]
+pub async fn get_cycles(params: QueryParams<CycleQueryParams>) -> Result<Json<Cycles>, ApiError> {
+    query_cycles(Json(params.0)).await
+}
+
+#[post("/root-causes", format = "json", data = "<params>")]
+pub async fn query_root_causes(
+    params: Json<GraphQueryParams>,
+) -> Result<Json<RootCauses>, ApiError> {
+    let mut client = get_client().await?;
+
+    Ok(Json(db::query_root_causes(&mut client, &params).await?))
+}
+
+#[get("/root-causes")]
+pub async fn get_root_causes(
+    params: QueryParams<GraphQueryParams>,
+) -> Result<Json<RootCauses>, ApiError> {
+    query_root_causes(Json(params.0)).await
+}
+
+#[post("/graph-diff", format = "json", data = "<params>")]
+pub async fn query_graph_diff(
+    params: Json<GraphDiffQueryParams>,
+) -> Result<Json<GraphDiff>, ApiError> {
+    let mut client = get_client().await?;
+
+    Ok(Json(db::query_graph_diff(&mut client, &params).await?))
+}
+
+#[get("/graph-diff")]
+pub async fn get_graph_diff(
+    params: QueryParams<GraphDiffQueryParams>,
+) -> Result<Json<GraphDiff>, ApiError> {
+    query_graph_diff(Json(params.0)).await
+}
+
+fn parse_node_id(node_id: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(node_id).map_err(|_| anyhow::anyhow!("invalid node id {}", node_id))
+}
+
+#[post("/nodes/search", format = "json", data = "<params>")]
+pub async fn query_node_search(
+    params: Json<NodeSearchParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<NodeSearchResults>, ApiError> {
+    let mut client = get_client().await?;
+    let limit = limits.page_size(Some(params.limit));
+
+    Ok(Json(db::search_nodes(&mut client, &params, limit).await?))
+}
+
+#[get("/nodes/search")]
+pub async fn get_node_search(
+    params: QueryParams<NodeSearchParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<NodeSearchResults>, ApiError> {
+    query_node_search(Json(params.0), limits).await
+}
+
+#[post("/nodes/<node_id>", format = "json", data = "<params>")]
+pub async fn query_node(
+    node_id: &str,
+    params: Json<CommonQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Option<Json<NodeDetail>>, ApiError> {
+    let node_id = parse_node_id(node_id)?;
+    let mut client = get_client().await?;
+    Ok(
+        db::query_node_detail(&mut client, node_id, &params, limits.max_histogram_buckets)
+            .await?
+            .map(Json),
+    )
+}
+
+#[get("/nodes/<node_id>")]
+pub async fn get_node(
+    node_id: &str,
+    params: QueryParams<CommonQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Option<Json<NodeDetail>>, ApiError> {
+    query_node(node_id, Json(params.0), limits).await
 }
 
 #[post("/histogram", format = "json", data = "<params>")]
-pub async fn query_histogram(params: Json<CommonQueryParams>) -> Result<Json<Histogram>, ApiError> {
+pub async fn query_histogram(
+    params: Json<HistogramQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<Histogram>, ApiError> {
     let mut client = get_client().await?;
 
-    Ok(Json(db::query_histogram(&mut client, &params).await?))
+    Ok(Json(
+        db::query_histogram(&mut client, &params, limits.max_histogram_buckets).await?,
+    ))
+}
+
+#[get("/histogram")]
+pub async fn get_histogram(
+    params: QueryParams<HistogramQueryParams>,
+    limits: &State<ResponseLimits>,
+) -> Result<Json<Histogram>, ApiError> {
+    query_histogram(Json(params.0), limits).await
 }
@@ -3,28 +3,40 @@ use std::io::Cursor;
 pub use anyhow::Error;
 
 use rocket::{
-    http::ContentType,
+    http::{ContentType, Status},
     response::{self, Responder},
     Request, Response,
 };
 
 pub struct ApiError {
     error: Error,
+    status: Status,
+}
+
+impl ApiError {
+    /// An error answered with `status` rather than the default 200.
+    pub fn with_status(status: Status, error: Error) -> ApiError {
+        ApiError { error, status }
+    }
 }
 
 impl From<Error> for ApiError {
     fn from(error: Error) -> ApiError {
-        dbg!(&error);
-        ApiError { error }
+        ApiError {
+            error,
+            status: Status::Ok,
+        }
     }
 }
 
 impl<'r> Responder<'r, 'static> for ApiError {
-    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        error!("{} {}: {:#}", request.method(), request.uri(), self.error);
         let error = format!("error: {}", self.error);
         Response::build()
             .sized_body(error.len(), Cursor::new(error))
             .header(ContentType::new("text", "plain"))
+            .status(self.status)
             .ok()
     }
 }
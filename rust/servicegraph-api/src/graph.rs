@@ -0,0 +1,1798 @@
+//! Traversals and rewrites of an already queried `Graph`.
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::mem;
+
+use uuid::Uuid;
+
+use crate::payloads::{
+    BlastRadius, Centrality, CombinedEdge, Cycle, Cycles, EdgeChange, Graph, GraphDiff, GraphView,
+    ImpactedNode, NodeType, NodeWithStatus, Path, Paths, Rates, RootCause, RootCauses,
+    StatusCounts, TrafficFilter,
+};
+
+/// Upper bound of paths enumerated per query, the number of simple paths
+/// grows exponentially with the size of the graph.
+const MAX_PATHS: usize = 1000;
+
+/// Longest path searched for, in edges.
+pub const MAX_PATH_HOPS: u32 = 16;
+
+/// Upper bound of partial paths extended per query, so that searching a
+/// dense graph stays cheap even when few paths reach the target.
+const MAX_PATH_EXPANSIONS: usize = 100_000;
+
+/// Upper bound of rounds spent propagating shares through cycles.
+const MAX_SHARE_ITERATIONS: usize = 100;
+
+/// Direction in which edges are followed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    /// From callers to callees.
+    Downstream,
+    /// From callees to callers.
+    Upstream,
+}
+
+/// Index of the edges of a graph by the node they start and end at.
+pub struct Adjacency<'a> {
+    outgoing: HashMap<Uuid, Vec<&'a CombinedEdge>>,
+    incoming: HashMap<Uuid, Vec<&'a CombinedEdge>>,
+}
+
+impl<'a> Adjacency<'a> {
+    pub fn new(edges: &'a [CombinedEdge]) -> Adjacency<'a> {
+        let mut outgoing: HashMap<Uuid, Vec<&CombinedEdge>> = HashMap::new();
+        let mut incoming: HashMap<Uuid, Vec<&CombinedEdge>> = HashMap::new();
+        for edge in edges {
+            outgoing.entry(edge.from_node_id).or_default().push(edge);
+            incoming.entry(edge.to_node_id).or_default().push(edge);
+        }
+        Adjacency { outgoing, incoming }
+    }
+
+    /// Returns the edges leaving `node_id` in the given direction.
+    pub fn edges(&self, node_id: Uuid, direction: Direction) -> &[&'a CombinedEdge] {
+        let map = match direction {
+            Direction::Downstream => &self.outgoing,
+            Direction::Upstream => &self.incoming,
+        };
+        map.get(&node_id).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
+/// Returns the node on the far side of `edge` when walking in `direction`.
+pub fn other_end(edge: &CombinedEdge, direction: Direction) -> Uuid {
+    match direction {
+        Direction::Downstream => edge.to_node_id,
+        Direction::Upstream => edge.from_node_id,
+    }
+}
+
+/// Hop distances of all nodes reachable from `start` within `max_hops`.
+pub fn distances(
+    adjacency: &Adjacency,
+    start: Uuid,
+    direction: Direction,
+    max_hops: u32,
+) -> HashMap<Uuid, u32> {
+    let mut rv = HashMap::new();
+    let mut queue = VecDeque::new();
+    rv.insert(start, 0);
+    queue.push_back(start);
+    while let Some(node_id) = queue.pop_front() {
+        let hops = rv[&node_id];
+        if hops >= max_hops {
+            continue;
+        }
+        for edge in adjacency.edges(node_id, direction) {
+            let next = other_end(edge, direction);
+            if let Entry::Vacant(entry) = rv.entry(next) {
+                entry.insert(hops + 1);
+                queue.push_back(next);
+            }
+        }
+    }
+    rv
+}
+
+/// Cuts `graph` down to what is reachable from `node_id` within the given
+/// number of hops upstream and downstream.
+pub fn neighborhood(
+    graph: Graph,
+    node_id: Uuid,
+    upstream_hops: u32,
+    downstream_hops: u32,
+) -> Graph {
+    let adjacency = Adjacency::new(&graph.edges);
+    let upstream = distances(&adjacency, node_id, Direction::Upstream, upstream_hops);
+    let downstream = distances(&adjacency, node_id, Direction::Downstream, downstream_hops);
+
+    // only keep the edges that were walked to reach a node
+    let walked = |edge: &CombinedEdge| {
+        matches!(downstream.get(&edge.from_node_id), Some(&hops) if hops < downstream_hops)
+            || matches!(upstream.get(&edge.to_node_id), Some(&hops) if hops < upstream_hops)
+    };
+    let edges: Vec<CombinedEdge> = graph.edges.iter().filter(|x| walked(x)).cloned().collect();
+
+    let mut node_ids: HashSet<Uuid> = upstream.keys().chain(downstream.keys()).copied().collect();
+    node_ids.insert(node_id);
+    let nodes = graph
+        .nodes
+        .into_iter()
+        .filter(|x| node_ids.contains(&x.node.node_id))
+        .collect();
+
+    Graph {
+        edges,
+        nodes,
+        truncated: graph.truncated,
+    }
+}
+
+/// Number of calls recorded on an edge, regardless of status.
+pub fn edge_total(edge: &CombinedEdge) -> u64 {
+    edge.status_ok + edge.status_expected_error + edge.status_unexpected_error
+}
+
+/// Enumerates simple paths from `from` to `to` with at most `max_hops` edges,
+/// busiest first (by their quietest hop), shorter ones first among equals.
+///
+/// The search is best first, so whatever it finds before stopping are the
+/// top paths.  It stops after `max_paths` paths or `MAX_PATH_EXPANSIONS`
+/// partial paths, the second return value tells whether more paths may
+/// exist.
+pub fn simple_paths<'a>(
+    adjacency: &Adjacency<'a>,
+    from: Uuid,
+    to: Uuid,
+    max_hops: u32,
+    max_paths: usize,
+) -> (Vec<Vec<&'a CombinedEdge>>, bool) {
+    let max_hops = max_hops.min(MAX_PATH_HOPS);
+    // nodes that cannot reach `to` in the remaining hops are never entered
+    let remaining = distances(adjacency, to, Direction::Upstream, max_hops);
+    if !remaining.contains_key(&from) {
+        return (Vec::new(), false);
+    }
+
+    // partial paths by (bottleneck traffic, fewest hops); extending a path
+    // never raises its traffic, so complete paths come out in rank order
+    let mut partial: Vec<Vec<&'a CombinedEdge>> = vec![Vec::new()];
+    let mut queue = BinaryHeap::new();
+    queue.push((u64::MAX, Reverse(0), Reverse(0)));
+    let mut found = Vec::new();
+    let mut expansions = 0;
+    while let Some((traffic, Reverse(hops), Reverse(idx))) = queue.pop() {
+        let path = mem::take(&mut partial[idx]);
+        let node_id = path.last().map_or(from, |x| x.to_node_id);
+        if node_id == to {
+            if found.len() == max_paths {
+                return (found, true);
+            }
+            found.push(path);
+            continue;
+        }
+        if expansions == MAX_PATH_EXPANSIONS {
+            return (found, true);
+        }
+        expansions += 1;
+
+        for &edge in adjacency.edges(node_id, Direction::Downstream) {
+            let next = edge.to_node_id;
+            let fits =
+                matches!(remaining.get(&next), Some(&left) if hops as u32 + 1 + left <= max_hops);
+            let visited = next == from || path.iter().any(|x| x.to_node_id == next);
+            if !fits || visited {
+                continue;
+            }
+            let mut extended = path.clone();
+            extended.push(edge);
+            queue.push((
+                traffic.min(edge_total(edge)),
+                Reverse(hops + 1),
+                Reverse(partial.len()),
+            ));
+            partial.push(extended);
+        }
+    }
+    (found, false)
+}
+
+/// Finds the paths from `from` to `to`, busiest first.
+pub fn paths(graph: Graph, from: Uuid, to: Uuid, max_hops: u32, limit: Option<usize>) -> Paths {
+    let adjacency = Adjacency::new(&graph.edges);
+    let max_paths = limit.unwrap_or(MAX_PATHS).min(MAX_PATHS);
+    let (found, truncated) = simple_paths(&adjacency, from, to, max_hops, max_paths);
+
+    let paths: Vec<Path> = found
+        .into_iter()
+        .map(|hops| Path {
+            traffic: hops.iter().map(|x| edge_total(x)).min().unwrap_or(0),
+            hops: hops.into_iter().cloned().collect(),
+        })
+        .collect();
+
+    let node_ids: HashSet<Uuid> = paths
+        .iter()
+        .flat_map(|x| x.hops.iter())
+        .flat_map(|x| vec![x.from_node_id, x.to_node_id])
+        .collect();
+    let nodes = graph
+        .nodes
+        .into_iter()
+        .filter(|x| node_ids.contains(&x.node.node_id))
+        .collect();
+
+    Paths {
+        paths,
+        nodes,
+        truncated,
+    }
+}
+
+/// Ranks the nodes that transitively call `node_id` by the share of their
+/// outgoing calls that end up at it.
+pub fn blast_radius(graph: Graph, node_id: Uuid) -> BlastRadius {
+    let adjacency = Adjacency::new(&graph.edges);
+    let upstream = distances(&adjacency, node_id, Direction::Upstream, u32::MAX);
+
+    let out_totals: HashMap<Uuid, u64> = upstream
+        .keys()
+        .map(|&id| {
+            let total = adjacency
+                .edges(id, Direction::Downstream)
+                .iter()
+                .map(|x| edge_total(x))
+                .sum();
+            (id, total)
+        })
+        .collect();
+
+    // share(x) = sum over the edges of x of share(callee) weighted by the
+    // fraction of calls on that edge.  Cycles make this a fixed point which
+    // is approached by iterating.
+    let mut shares: HashMap<Uuid, f64> = upstream.keys().map(|&id| (id, 0.0)).collect();
+    shares.insert(node_id, 1.0);
+    for _ in 0..MAX_SHARE_ITERATIONS {
+        let mut changed = false;
+        for (&id, &total) in &out_totals {
+            if id == node_id || total == 0 {
+                continue;
+            }
+            let share: f64 = adjacency
+                .edges(id, Direction::Downstream)
+                .iter()
+                .map(|x| {
+                    edge_total(x) as f64 / total as f64
+                        * shares.get(&x.to_node_id).copied().unwrap_or(0.0)
+                })
+                .sum();
+            if (share - shares[&id]).abs() > 1e-9 {
+                changed = true;
+            }
+            shares.insert(id, share);
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut impacted: Vec<ImpactedNode> = graph
+        .nodes
+        .into_iter()
+        .filter(|x| x.node.node_id != node_id)
+        .filter_map(|node| {
+            let id = node.node.node_id;
+            let hops = *upstream.get(&id)?;
+            let share = shares[&id];
+            Some(ImpactedNode {
+                node,
+                hops,
+                share,
+                traffic: (out_totals[&id] as f64 * share).round() as u64,
+            })
+        })
+        .collect();
+    impacted.sort_by(|a, b| {
+        b.share
+            .partial_cmp(&a.share)
+            .unwrap_or(Ordering::Equal)
+            .then(b.traffic.cmp(&a.traffic))
+            .then(a.hops.cmp(&b.hops))
+    });
+
+    BlastRadius { node_id, impacted }
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+/// Share of the calls made by `node_id` that failed unexpectedly.
+fn downstream_error_ratio(adjacency: &Adjacency, node_id: Uuid) -> f64 {
+    let outgoing = adjacency.edges(node_id, Direction::Downstream);
+    ratio(
+        outgoing.iter().map(|x| x.status_unexpected_error).sum(),
+        outgoing.iter().map(|x| edge_total(x)).sum(),
+    )
+}
+
+/// Traces the unexpected errors received by `node_id` back to where they
+/// originate.
+///
+/// A node keeps the part of its errors that its own calls don't explain and
+/// passes the rest on to its callees, in proportion to the errors on the edges
+/// to them.  Returns the share of the errors each node ends up with.
+fn error_origins(
+    adjacency: &Adjacency,
+    downstream: &HashMap<Uuid, f64>,
+    node_id: Uuid,
+) -> HashMap<Uuid, f64> {
+    let mut origins: HashMap<Uuid, f64> = HashMap::new();
+    let mut frontier: HashMap<Uuid, f64> = HashMap::new();
+    frontier.insert(node_id, 1.0);
+    for _ in 0..MAX_SHARE_ITERATIONS {
+        let mut next: HashMap<Uuid, f64> = HashMap::new();
+        for (id, share) in frontier.drain() {
+            let failing: Vec<&CombinedEdge> = adjacency
+                .edges(id, Direction::Downstream)
+                .iter()
+                .copied()
+                .filter(|x| x.status_unexpected_error > 0)
+                .collect();
+            let errors: u64 = failing.iter().map(|x| x.status_unexpected_error).sum();
+            let passed = if errors == 0 {
+                0.0
+            } else {
+                downstream.get(&id).copied().unwrap_or(0.0)
+            };
+            *origins.entry(id).or_insert(0.0) += share * (1.0 - passed);
+            for edge in failing {
+                *next.entry(edge.to_node_id).or_insert(0.0) +=
+                    share * passed * edge.status_unexpected_error as f64 / errors as f64;
+            }
+        }
+        next.retain(|_, share| *share > 1e-9);
+        frontier = next;
+        if frontier.is_empty() {
+            break;
+        }
+    }
+    // whatever still goes around in cycles stays where it is
+    for (id, share) in frontier {
+        *origins.entry(id).or_insert(0.0) += share;
+    }
+    origins
+}
+
+/// Ranks the nodes receiving unexpected errors by how likely they are to be
+/// where the errors originate.
+///
+/// The errors of every node are traced down the failing calls to the nodes
+/// whose own callees don't explain them (see `error_origins`).  A node is
+/// suspicious in proportion to the error ratio of its inbound calls and the
+/// share of its errors that stay with it, so failing nodes whose callees are
+/// healthy (or that call nothing) rank highest.  Ties go to the node most
+/// errors in the graph trace back to.
+pub fn root_causes(graph: Graph) -> RootCauses {
+    let adjacency = Adjacency::new(&graph.edges);
+    let names: HashMap<Uuid, &str> = graph
+        .nodes
+        .iter()
+        .map(|x| (x.node.node_id, x.node.name.as_str()))
+        .collect();
+    let downstream: HashMap<Uuid, f64> = graph
+        .nodes
+        .iter()
+        .map(|x| {
+            let node_id = x.node.node_id;
+            (node_id, downstream_error_ratio(&adjacency, node_id))
+        })
+        .collect();
+
+    let origins: HashMap<Uuid, HashMap<Uuid, f64>> = graph
+        .nodes
+        .iter()
+        .filter(|x| x.inbound.status_unexpected_error > 0)
+        .map(|x| {
+            let node_id = x.node.node_id;
+            (node_id, error_origins(&adjacency, &downstream, node_id))
+        })
+        .collect();
+    let mut attributed: HashMap<Uuid, f64> = HashMap::new();
+    for node in &graph.nodes {
+        if let Some(shares) = origins.get(&node.node.node_id) {
+            for (&id, &share) in shares {
+                *attributed.entry(id).or_insert(0.0) +=
+                    node.inbound.status_unexpected_error as f64 * share;
+            }
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for node in &graph.nodes {
+        let node_id = node.node.node_id;
+        let shares = match origins.get(&node_id) {
+            Some(shares) => shares,
+            None => continue,
+        };
+        let inbound_errors = node.inbound.status_unexpected_error;
+        let inbound_total =
+            node.inbound.status_ok + node.inbound.status_expected_error + inbound_errors;
+        let inbound_error_ratio = ratio(inbound_errors, inbound_total);
+        let own_share = shares.get(&node_id).copied().unwrap_or(0.0);
+
+        let outgoing = adjacency.edges(node_id, Direction::Downstream);
+        let outgoing_total: u64 = outgoing.iter().map(|x| edge_total(x)).sum();
+        let downstream_error_ratio = downstream[&node_id];
+        let origin = shares
+            .iter()
+            .filter(|(&id, _)| id != node_id)
+            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(Ordering::Equal));
+
+        let mut explanation = format!(
+            "{:.1}% of {} inbound calls failed unexpectedly",
+            inbound_error_ratio * 100.0,
+            inbound_total
+        );
+        match origin {
+            None if outgoing.is_empty() => explanation.push_str(", it calls no other node"),
+            None => write!(
+                explanation,
+                ", all of its {} outgoing calls succeeded",
+                outgoing_total
+            )
+            .unwrap(),
+            Some((origin_id, origin_share)) => write!(
+                explanation,
+                ", {:.1}% of its outgoing calls failed too, {:.1}% of its failures trace back to {}",
+                downstream_error_ratio * 100.0,
+                origin_share * 100.0,
+                names.get(origin_id).copied().unwrap_or("an unknown node"),
+            )
+            .unwrap(),
+        }
+
+        candidates.push(RootCause {
+            node: node.clone(),
+            score: inbound_error_ratio * own_share,
+            inbound_error_ratio,
+            downstream_error_ratio,
+            attributed_errors: attributed.get(&node_id).copied().unwrap_or(0.0).round() as u64,
+            explanation,
+        });
+    }
+
+    candidates.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then(b.attributed_errors.cmp(&a.attributed_errors))
+            .then(
+                b.node
+                    .inbound
+                    .status_unexpected_error
+                    .cmp(&a.node.inbound.status_unexpected_error),
+            )
+    });
+
+    RootCauses { candidates }
+}
+
+/// Compares the graphs of two time windows.
+///
+/// The rates of the edges are expected to be filled in, volumes are compared
+/// per minute.
+pub fn diff(
+    before: Graph,
+    after: Graph,
+    volume_threshold: f64,
+    error_ratio_threshold: f64,
+) -> GraphDiff {
+    let before_node_ids: HashSet<Uuid> = before.nodes.iter().map(|x| x.node.node_id).collect();
+    let after_node_ids: HashSet<Uuid> = after.nodes.iter().map(|x| x.node.node_id).collect();
+    let added_nodes = after
+        .nodes
+        .into_iter()
+        .filter(|x| !before_node_ids.contains(&x.node.node_id))
+        .collect();
+    let removed_nodes = before
+        .nodes
+        .into_iter()
+        .filter(|x| !after_node_ids.contains(&x.node.node_id))
+        .collect();
+
+    let mut before_edges: HashMap<(Uuid, Uuid), CombinedEdge> = before
+        .edges
+        .into_iter()
+        .map(|x| ((x.from_node_id, x.to_node_id), x))
+        .collect();
+    let mut added_edges = Vec::new();
+    let mut changed_edges = Vec::new();
+    for edge in after.edges {
+        let old = match before_edges.remove(&(edge.from_node_id, edge.to_node_id)) {
+            Some(old) => old,
+            None => {
+                added_edges.push(edge);
+                continue;
+            }
+        };
+        // the windows need not be equally long
+        let (old_total, new_total) = (edge_total(&old), edge_total(&edge));
+        let (old_rpm, new_rpm) = (
+            old.rates.requests_per_minute,
+            edge.rates.requests_per_minute,
+        );
+        let volume_change = if old_total == 0 {
+            None
+        } else {
+            Some((new_rpm - old_rpm) / old_rpm)
+        };
+        let error_ratio_change = ratio(edge.status_unexpected_error, new_total)
+            - ratio(old.status_unexpected_error, old_total);
+        let volume_changed = match volume_change {
+            Some(change) => change.abs() > volume_threshold,
+            None => new_total > 0,
+        };
+        if volume_changed || error_ratio_change.abs() > error_ratio_threshold {
+            changed_edges.push(EdgeChange {
+                before: old,
+                after: edge,
+                volume_change,
+                error_ratio_change,
+            });
+        }
+    }
+    let removed_edges = before_edges.into_values().collect();
+
+    GraphDiff {
+        added_nodes,
+        removed_nodes,
+        added_edges,
+        removed_edges,
+        changed_edges,
+    }
+}
+
+/// Maps the transactions of a graph to their parent services.
+fn parent_services(graph: &Graph) -> HashMap<Uuid, Uuid> {
+    graph
+        .nodes
+        .iter()
+        .filter(|x| x.node.node_type == NodeType::Transaction)
+        .filter_map(|x| Some((x.node.node_id, x.node.parent_id?)))
+        .collect()
+}
+
+/// Groups edges by the services at either end.
+///
+/// Every call shows up once per combination of levels between the same two
+/// services.
+fn group_calls<'a>(
+    edges: &'a [CombinedEdge],
+    parents: &HashMap<Uuid, Uuid>,
+) -> HashMap<(Uuid, Uuid), Vec<&'a CombinedEdge>> {
+    let service_of = |node_id: Uuid| parents.get(&node_id).copied().unwrap_or(node_id);
+    let mut calls: HashMap<(Uuid, Uuid), Vec<&CombinedEdge>> = HashMap::new();
+    for edge in edges {
+        calls
+            .entry((service_of(edge.from_node_id), service_of(edge.to_node_id)))
+            .or_default()
+            .push(edge);
+    }
+    calls
+}
+
+/// The edges of a graph rolled up to the services, including the self-loops
+/// of services whose transactions call each other or the service itself.
+pub fn service_edges(graph: &Graph) -> Vec<CombinedEdge> {
+    let parents = parent_services(graph);
+    let is_transaction = |node_id: Uuid| parents.contains_key(&node_id);
+    group_calls(&graph.edges, &parents)
+        .into_iter()
+        .map(|((from, to), edges)| roll_up(from, to, &edges, is_transaction))
+        .collect()
+}
+
+/// Tarjan's algorithm for strongly connected components.
+struct Components<'a, 'b> {
+    adjacency: &'b Adjacency<'a>,
+    index: HashMap<Uuid, usize>,
+    lowlink: HashMap<Uuid, usize>,
+    stack: Vec<Uuid>,
+    on_stack: HashSet<Uuid>,
+    components: Vec<Vec<Uuid>>,
+}
+
+impl<'a, 'b> Components<'a, 'b> {
+    fn enter(&mut self, node_id: Uuid) {
+        let index = self.index.len();
+        self.index.insert(node_id, index);
+        self.lowlink.insert(node_id, index);
+        self.stack.push(node_id);
+        self.on_stack.insert(node_id);
+    }
+
+    fn lower(&mut self, node_id: Uuid, lowlink: usize) {
+        let lowlink = self.lowlink[&node_id].min(lowlink);
+        self.lowlink.insert(node_id, lowlink);
+    }
+
+    /// Visits everything reachable from `start`.
+    ///
+    /// The depth first search keeps its own stack of nodes and the next edge
+    /// to follow from each, call chains can be deeper than the thread's stack.
+    fn visit(&mut self, start: Uuid) {
+        self.enter(start);
+        let mut calls = vec![(start, 0)];
+        while let Some(frame) = calls.last_mut() {
+            let node_id = frame.0;
+            let edge = self
+                .adjacency
+                .edges(node_id, Direction::Downstream)
+                .get(frame.1)
+                .copied();
+            frame.1 += 1;
+
+            if let Some(edge) = edge {
+                let next = edge.to_node_id;
+                if !self.index.contains_key(&next) {
+                    self.enter(next);
+                    calls.push((next, 0));
+                } else if self.on_stack.contains(&next) {
+                    self.lower(node_id, self.index[&next]);
+                }
+                continue;
+            }
+
+            calls.pop();
+            if self.lowlink[&node_id] == self.index[&node_id] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(&member);
+                    component.push(member);
+                    if member == node_id {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+            if let Some(&(caller, _)) = calls.last() {
+                self.lower(caller, self.lowlink[&node_id]);
+            }
+        }
+    }
+}
+
+/// Finds the cycles between services.
+///
+/// Every strongly connected component of the service level graph with more
+/// than one service is a cycle, as is a service calling itself unless
+/// `ignore_self_loops` is set.  Self-loops mostly come from transactions
+/// calling other transactions of the same service.
+pub fn cycles(graph: Graph, ignore_self_loops: bool) -> Cycles {
+    let mut edges = service_edges(&graph);
+    if ignore_self_loops {
+        edges.retain(|x| x.from_node_id != x.to_node_id);
+    }
+    let adjacency = Adjacency::new(&edges);
+
+    let mut components = Components {
+        adjacency: &adjacency,
+        index: HashMap::new(),
+        lowlink: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        components: Vec::new(),
+    };
+    for edge in &edges {
+        if !components.index.contains_key(&edge.from_node_id) {
+            components.visit(edge.from_node_id);
+        }
+    }
+
+    let nodes: HashMap<Uuid, &NodeWithStatus> =
+        graph.nodes.iter().map(|x| (x.node.node_id, x)).collect();
+    let mut rv: Vec<Cycle> = components
+        .components
+        .into_iter()
+        .filter_map(|members| {
+            let members: HashSet<Uuid> = members.into_iter().collect();
+            let cycle_edges: Vec<CombinedEdge> = edges
+                .iter()
+                .filter(|x| members.contains(&x.from_node_id) && members.contains(&x.to_node_id))
+                .cloned()
+                .collect();
+            if members.len() < 2 && cycle_edges.is_empty() {
+                return None;
+            }
+            Some(Cycle {
+                nodes: members
+                    .iter()
+                    .filter_map(|x| nodes.get(x).map(|x| (*x).clone()))
+                    .collect(),
+                traffic: cycle_edges.iter().map(edge_total).sum(),
+                edges: cycle_edges,
+            })
+        })
+        .collect();
+    rv.sort_by_key(|x| Reverse(x.traffic));
+
+    Cycles { cycles: rv }
+}
+
+/// Rewrites a raw graph into the requested view, the statuses of the nodes
+/// are recomputed from the edges of the view.
+///
+/// The parents of all transactions are expected to be part of `graph`.
+pub fn apply_view(graph: Graph, view: GraphView) -> Graph {
+    let parents = parent_services(&graph);
+    let service_of = |node_id: Uuid| parents.get(&node_id).copied().unwrap_or(node_id);
+    let is_transaction = |node_id: Uuid| parents.contains_key(&node_id);
+
+    let edges = match view {
+        GraphView::Service => service_edges(&graph)
+            .into_iter()
+            .filter(|x| x.from_node_id != x.to_node_id)
+            .collect(),
+        GraphView::Transaction | GraphView::Mixed => {
+            let mut rv = Vec::new();
+            for edges in group_calls(&graph.edges, &parents).values() {
+                let from_transactions = edges.iter().any(|x| is_transaction(x.from_node_id));
+                let to_transactions = edges.iter().any(|x| is_transaction(x.to_node_id));
+                rv.extend(
+                    edges
+                        .iter()
+                        .filter(|x| is_transaction(x.from_node_id) || !from_transactions)
+                        .filter(|x| is_transaction(x.to_node_id) || !to_transactions)
+                        .map(|x| (*x).clone()),
+                );
+            }
+            rv
+        }
+    };
+
+    let mut keep: HashSet<Uuid> = HashSet::new();
+    for edge in &edges {
+        keep.insert(edge.from_node_id);
+        keep.insert(edge.to_node_id);
+    }
+    if view == GraphView::Mixed {
+        let parents: Vec<Uuid> = keep.iter().map(|&x| service_of(x)).collect();
+        keep.extend(parents);
+    }
+    let nodes = graph
+        .nodes
+        .into_iter()
+        .filter(|x| keep.contains(&x.node.node_id))
+        .collect();
+
+    let mut graph = Graph {
+        edges,
+        nodes,
+        truncated: graph.truncated,
+    };
+    sum_node_statuses(&mut graph);
+    graph
+}
+
+/// Merges the edges of the calls between two services.
+///
+/// Each level (service or transaction on either end) sees every call once, so
+/// the counts are the maximum over the levels rather than the sum.
+fn roll_up(
+    from: Uuid,
+    to: Uuid,
+    edges: &[&CombinedEdge],
+    is_transaction: impl Fn(Uuid) -> bool,
+) -> CombinedEdge {
+    let mut levels: HashMap<(bool, bool), (u64, u64, u64)> = HashMap::new();
+    for edge in edges {
+        let level = levels
+            .entry((
+                is_transaction(edge.from_node_id),
+                is_transaction(edge.to_node_id),
+            ))
+            .or_default();
+        level.0 += edge.status_ok;
+        level.1 += edge.status_expected_error;
+        level.2 += edge.status_unexpected_error;
+    }
+    let service_edge = edges
+        .iter()
+        .find(|x| x.from_node_id == from && x.to_node_id == to)
+        .unwrap_or(&edges[0]);
+    CombinedEdge {
+        from_node_id: from,
+        to_node_id: to,
+        description: service_edge.description.clone(),
+        class: service_edge.class.clone(),
+        status_ok: levels.values().map(|x| x.0).max().unwrap_or(0),
+        status_expected_error: levels.values().map(|x| x.1).max().unwrap_or(0),
+        status_unexpected_error: levels.values().map(|x| x.2).max().unwrap_or(0),
+        extrapolated: edges.iter().any(|x| x.extrapolated),
+        rates: Rates::default(),
+    }
+}
+
+/// Recomputes the inbound and outbound statuses of the nodes from their
+/// edges.
+pub fn sum_node_statuses(graph: &mut Graph) {
+    let mut inbound: HashMap<Uuid, (StatusCounts, bool)> = HashMap::new();
+    let mut outbound: HashMap<Uuid, StatusCounts> = HashMap::new();
+    for edge in &graph.edges {
+        let status = inbound.entry(edge.to_node_id).or_default();
+        status.0.add_edge(edge);
+        status.1 |= edge.extrapolated;
+        outbound
+            .entry(edge.from_node_id)
+            .or_default()
+            .add_edge(edge);
+    }
+    for node in &mut graph.nodes {
+        let node_id = node.node.node_id;
+        let (status, extrapolated) = inbound.get(&node_id).copied().unwrap_or_default();
+        node.inbound = status;
+        node.extrapolated = extrapolated;
+        node.outbound = outbound.get(&node_id).copied().unwrap_or_default();
+    }
+}
+
+/// Rolls the statuses of transactions up into their parent services.
+///
+/// Calls are reported on the services as well as on the transactions
+/// involved, so a service ends up with the larger of its own counts and the
+/// sums over its transactions.
+pub fn roll_up_transactions(graph: &mut Graph) {
+    let mut children: HashMap<Uuid, (StatusCounts, StatusCounts, bool)> = HashMap::new();
+    for node in &graph.nodes {
+        if let (NodeType::Transaction, Some(parent_id)) = (node.node.node_type, node.node.parent_id)
+        {
+            let sums = children.entry(parent_id).or_default();
+            sums.0.add(node.inbound);
+            sums.1.add(node.outbound);
+            sums.2 |= node.extrapolated;
+        }
+    }
+    for node in &mut graph.nodes {
+        if let Some(&(inbound, outbound, extrapolated)) = children.get(&node.node.node_id) {
+            node.inbound = node.inbound.max(inbound);
+            node.outbound = node.outbound.max(outbound);
+            node.extrapolated |= extrapolated;
+        }
+    }
+}
+
+/// Computes the rates of all nodes and edges from their status counts.
+///
+/// `minutes` is the length of the queried window.
+pub fn add_rates(graph: &mut Graph, minutes: f64) {
+    for edge in &mut graph.edges {
+        edge.rates = Rates::new(
+            edge.status_ok,
+            edge.status_expected_error,
+            edge.status_unexpected_error,
+            minutes,
+        );
+    }
+    for node in &mut graph.nodes {
+        node.rates = Rates::new(
+            node.inbound.status_ok,
+            node.inbound.status_expected_error,
+            node.inbound.status_unexpected_error,
+            minutes,
+        );
+    }
+}
+
+/// Drops the edges not passing `filter` and the nodes left without edges.
+/// The statuses of the remaining nodes only count the remaining edges.
+///
+/// `minutes` is the length of the queried window, used for the calls per
+/// minute.
+pub fn filter_traffic(graph: Graph, filter: &TrafficFilter, minutes: f64) -> Graph {
+    if filter.is_empty() {
+        return graph;
+    }
+
+    let mut edges = graph.edges;
+    if let Some(min_rpm) = filter.min_rpm {
+        edges.retain(|x| edge_total(x) as f64 / minutes.max(1.0) >= min_rpm);
+    }
+    if let Some(min_error_ratio) = filter.min_error_ratio {
+        edges.retain(|x| ratio(x.status_unexpected_error, edge_total(x)) >= min_error_ratio);
+    }
+    if let Some(min_percentile) = filter.min_percentile {
+        let mut volumes: Vec<u64> = edges.iter().map(edge_total).collect();
+        volumes.sort_unstable();
+        if !volumes.is_empty() {
+            // nearest rank
+            let rank = (min_percentile.clamp(0.0, 100.0) / 100.0 * volumes.len() as f64).ceil();
+            let cutoff = volumes[(rank as usize).max(1) - 1];
+            edges.retain(|x| edge_total(x) >= cutoff);
+        }
+    }
+    if let Some(traffic_volume) = filter.traffic_volume {
+        let min_volume = edges.iter().map(edge_total).min().unwrap_or(0);
+        let max_volume = edges.iter().map(edge_total).max().unwrap_or(0);
+        // with all edges equally busy there is nothing to tell apart
+        if max_volume > min_volume {
+            let traffic_volume = traffic_volume.min(100) as f64;
+            edges.retain(|x| {
+                let percentage =
+                    (edge_total(x) - min_volume) as f64 / (max_volume - min_volume) as f64 * 100.0;
+                percentage >= traffic_volume
+            });
+        }
+    }
+    if let Some(top_edges) = filter.top_edges {
+        edges.sort_by_key(|x| Reverse(edge_total(x)));
+        edges.truncate(top_edges);
+    }
+
+    let mut keep: HashSet<Uuid> = HashSet::new();
+    for edge in &edges {
+        keep.insert(edge.from_node_id);
+        keep.insert(edge.to_node_id);
+    }
+    let nodes = graph
+        .nodes
+        .into_iter()
+        .filter(|x| keep.contains(&x.node.node_id))
+        .collect();
+
+    let mut graph = Graph {
+        edges,
+        nodes,
+        truncated: graph.truncated,
+    };
+    sum_node_statuses(&mut graph);
+    graph
+}
+
+/// Drops the quietest edges until the graph has at most `max_nodes` nodes and
+/// `max_edges` edges.  The remaining edges are ordered busiest first.
+///
+/// Parents without edges of their own (as added by the mixed view) are kept
+/// along with their children.
+pub fn truncate(graph: Graph, max_nodes: usize, max_edges: usize) -> Graph {
+    let mut edges = graph.edges;
+    edges.sort_by_key(|x| Reverse(edge_total(x)));
+
+    let mut connected: HashSet<Uuid> = HashSet::new();
+    for edge in &edges {
+        connected.insert(edge.from_node_id);
+        connected.insert(edge.to_node_id);
+    }
+    let edgeless: HashSet<Uuid> = graph
+        .nodes
+        .iter()
+        .map(|x| x.node.node_id)
+        .filter(|x| !connected.contains(x))
+        .collect();
+    let edgeless_parent: HashMap<Uuid, Uuid> = graph
+        .nodes
+        .iter()
+        .filter_map(|x| Some((x.node.node_id, x.node.parent_id?)))
+        .filter(|(_, parent_id)| edgeless.contains(parent_id))
+        .collect();
+
+    let mut keep: HashSet<Uuid> = HashSet::new();
+    let mut kept_edges = Vec::new();
+    let mut truncated = graph.truncated;
+    for edge in edges {
+        let mut new_nodes = Vec::new();
+        for &node_id in &[edge.from_node_id, edge.to_node_id] {
+            let parent_id = edgeless_parent.get(&node_id).copied();
+            for node_id in std::iter::once(node_id).chain(parent_id) {
+                if !keep.contains(&node_id) && !new_nodes.contains(&node_id) {
+                    new_nodes.push(node_id);
+                }
+            }
+        }
+        if kept_edges.len() >= max_edges || keep.len() + new_nodes.len() > max_nodes {
+            truncated = true;
+            continue;
+        }
+        keep.extend(new_nodes);
+        kept_edges.push(edge);
+    }
+    let nodes = graph
+        .nodes
+        .into_iter()
+        .filter(|x| keep.contains(&x.node.node_id))
+        .collect();
+
+    Graph {
+        edges: kept_edges,
+        nodes,
+        truncated,
+    }
+}
+
+const PAGERANK_DAMPING: f64 = 0.85;
+const MAX_PAGERANK_ITERATIONS: usize = 100;
+
+/// Fills in the centrality scores of all nodes of `graph`.
+pub fn add_centrality(graph: &mut Graph) {
+    let ids: Vec<Uuid> = graph.nodes.iter().map(|x| x.node.node_id).collect();
+    let index: HashMap<Uuid, usize> = ids.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    let n = ids.len();
+
+    // outgoing neighbors and their call counts, ignoring self-loops
+    let mut outgoing: Vec<Vec<(usize, u64)>> = vec![Vec::new(); n];
+    let mut in_degree = vec![0; n];
+    for edge in &graph.edges {
+        if let (Some(&from), Some(&to)) =
+            (index.get(&edge.from_node_id), index.get(&edge.to_node_id))
+        {
+            if from != to {
+                outgoing[from].push((to, edge_total(edge)));
+                in_degree[to] += 1;
+            }
+        }
+    }
+
+    let betweenness = betweenness(&outgoing);
+    let pagerank = pagerank(&outgoing);
+    for (i, node) in graph.nodes.iter_mut().enumerate() {
+        node.centrality = Some(Centrality {
+            in_degree: in_degree[i],
+            out_degree: outgoing[i].len(),
+            betweenness: betweenness[i],
+            pagerank: pagerank[i],
+        });
+    }
+}
+
+/// Brandes' algorithm on the unweighted directed graph, normalized to the
+/// number of ordered pairs of other nodes.
+fn betweenness(outgoing: &[Vec<(usize, u64)>]) -> Vec<f64> {
+    let n = outgoing.len();
+    let mut rv = vec![0.0; n];
+    for source in 0..n {
+        let mut order = Vec::new();
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); n];
+        let mut paths = vec![0.0; n];
+        let mut distance: Vec<Option<usize>> = vec![None; n];
+        paths[source] = 1.0;
+        distance[source] = Some(0);
+        let mut queue = VecDeque::new();
+        queue.push_back(source);
+        while let Some(v) = queue.pop_front() {
+            order.push(v);
+            let d = distance[v].unwrap_or(0);
+            for &(w, _) in &outgoing[v] {
+                if distance[w].is_none() {
+                    distance[w] = Some(d + 1);
+                    queue.push_back(w);
+                }
+                if distance[w] == Some(d + 1) {
+                    paths[w] += paths[v];
+                    predecessors[w].push(v);
+                }
+            }
+        }
+
+        let mut dependency = vec![0.0; n];
+        for &w in order.iter().rev() {
+            for &v in &predecessors[w] {
+                dependency[v] += paths[v] / paths[w] * (1.0 + dependency[w]);
+            }
+            if w != source {
+                rv[w] += dependency[w];
+            }
+        }
+    }
+
+    if n > 2 {
+        let pairs = ((n - 1) * (n - 2)) as f64;
+        for value in &mut rv {
+            *value /= pairs;
+        }
+    }
+    rv
+}
+
+/// PageRank where a node passes on its rank in proportion to the calls on its
+/// outgoing edges.  Nodes calling nothing spread their rank evenly.
+fn pagerank(outgoing: &[Vec<(usize, u64)>]) -> Vec<f64> {
+    let n = outgoing.len();
+    if n == 0 {
+        return vec![];
+    }
+    let totals: Vec<u64> = outgoing
+        .iter()
+        .map(|x| x.iter().map(|(_, calls)| calls).sum())
+        .collect();
+    let mut rank = vec![1.0 / n as f64; n];
+    for _ in 0..MAX_PAGERANK_ITERATIONS {
+        let dangling: f64 = (0..n).filter(|&v| totals[v] == 0).map(|v| rank[v]).sum();
+        let base = (1.0 - PAGERANK_DAMPING) / n as f64 + PAGERANK_DAMPING * dangling / n as f64;
+        let mut next = vec![base; n];
+        for v in 0..n {
+            if totals[v] == 0 {
+                continue;
+            }
+            for &(w, calls) in &outgoing[v] {
+                next[w] += PAGERANK_DAMPING * rank[v] * calls as f64 / totals[v] as f64;
+            }
+        }
+        let change: f64 = next.iter().zip(&rank).map(|(a, b)| (a - b).abs()).sum();
+        rank = next;
+        if change < 1e-9 {
+            break;
+        }
+    }
+    rank
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payloads::Node;
+
+    fn node_id(idx: u128) -> Uuid {
+        Uuid::from_u128(idx)
+    }
+
+    fn edge(from: u128, to: u128, status_ok: u64, status_unexpected_error: u64) -> CombinedEdge {
+        CombinedEdge {
+            from_node_id: node_id(from),
+            to_node_id: node_id(to),
+            description: None,
+            class: None,
+            status_ok,
+            status_expected_error: 0,
+            status_unexpected_error,
+            extrapolated: false,
+            rates: Rates::default(),
+        }
+    }
+
+    /// Builds a graph from `(from, to, ok, unexpected_error)` tuples.
+    fn graph(edges: &[(u128, u128, u64, u64)]) -> Graph {
+        let edges: Vec<CombinedEdge> = edges
+            .iter()
+            .map(|&(a, b, ok, err)| edge(a, b, ok, err))
+            .collect();
+        let mut ids: Vec<Uuid> = edges
+            .iter()
+            .flat_map(|x| vec![x.from_node_id, x.to_node_id])
+            .collect();
+        ids.sort();
+        ids.dedup();
+        let nodes = ids
+            .into_iter()
+            .map(|id| NodeWithStatus {
+                node: Node {
+                    node_id: id,
+                    node_type: NodeType::Service,
+                    name: format!("service_{}", id.as_u128()),
+                    description: None,
+                    class: None,
+                    parent_id: None,
+                },
+                inbound: StatusCounts::default(),
+                outbound: StatusCounts::default(),
+                extrapolated: false,
+                centrality: None,
+                rates: Rates::default(),
+            })
+            .collect();
+        Graph {
+            edges,
+            nodes,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_neighborhood() {
+        // 1 -> 2 -> 3 -> 4 -> 5, 6 -> 3
+        let g = graph(&[
+            (1, 2, 1, 0),
+            (2, 3, 1, 0),
+            (3, 4, 1, 0),
+            (4, 5, 1, 0),
+            (6, 3, 1, 0),
+        ]);
+        let sub = neighborhood(g, node_id(3), 1, 1);
+        let mut ids: Vec<u128> = sub.nodes.iter().map(|x| x.node.node_id.as_u128()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![2, 3, 4, 6]);
+        assert_eq!(sub.edges.len(), 3);
+
+        let g = graph(&[(1, 2, 1, 0), (2, 3, 1, 0), (3, 4, 1, 0), (4, 5, 1, 0)]);
+        let sub = neighborhood(g, node_id(3), 0, 2);
+        let mut ids: Vec<u128> = sub.nodes.iter().map(|x| x.node.node_id.as_u128()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![3, 4, 5]);
+        assert_eq!(sub.edges.len(), 2);
+    }
+
+    #[test]
+    fn test_simple_paths() {
+        // 1 -> 2 -> 4, 1 -> 3 -> 4, 2 -> 3, 4 -> 1
+        let g = graph(&[
+            (1, 2, 10, 0),
+            (2, 4, 5, 0),
+            (1, 3, 3, 0),
+            (3, 4, 3, 0),
+            (2, 3, 1, 0),
+            (4, 1, 1, 0),
+        ]);
+        let adjacency = Adjacency::new(&g.edges);
+        let (found, truncated) = simple_paths(&adjacency, node_id(1), node_id(4), 10, 100);
+        assert!(!truncated);
+        assert_eq!(found.len(), 3);
+        assert!(
+            found
+                .iter()
+                .all(|x| x[0].from_node_id == node_id(1)
+                    && x.last().unwrap().to_node_id == node_id(4))
+        );
+
+        let (found, _) = simple_paths(&adjacency, node_id(1), node_id(4), 2, 100);
+        assert_eq!(found.len(), 2);
+
+        let (found, truncated) = simple_paths(&adjacency, node_id(1), node_id(4), 10, 1);
+        assert_eq!(found.len(), 1);
+        assert!(truncated);
+
+        let (found, _) = simple_paths(&adjacency, node_id(3), node_id(2), 10, 100);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].len(), 3);
+
+        // exactly as many paths as asked for is not truncated
+        let (found, truncated) = simple_paths(&adjacency, node_id(1), node_id(4), 10, 3);
+        assert_eq!(found.len(), 3);
+        assert!(!truncated);
+
+        // 4 calls nothing, so nothing is searched from 1
+        let (found, truncated) = simple_paths(&adjacency, node_id(4), node_id(5), 10, 100);
+        assert!(found.is_empty());
+        assert!(!truncated);
+
+        let result = paths(g, node_id(1), node_id(4), 10, Some(2));
+        assert_eq!(result.paths.len(), 2);
+        assert_eq!(result.paths[0].traffic, 5);
+        assert_eq!(result.paths[1].traffic, 3);
+        assert_eq!(result.nodes.len(), 4);
+        assert!(result.truncated);
+    }
+
+    #[test]
+    fn test_paths_dense_graph() {
+        // a complete graph on 30 nodes without a way into 99
+        let mut edges = Vec::new();
+        for from in 1..=30 {
+            for to in 1..=30 {
+                if from != to {
+                    edges.push((from, to, (from * to) as u64, 0));
+                }
+            }
+        }
+        edges.push((99, 1, 1, 0));
+        let g = graph(&edges);
+        let adjacency = Adjacency::new(&g.edges);
+        let (found, truncated) = simple_paths(&adjacency, node_id(1), node_id(99), 1000, 10);
+        assert!(found.is_empty());
+        assert!(!truncated);
+
+        // the top paths come out in order even though there are far too many
+        let (found, truncated) = simple_paths(&adjacency, node_id(1), node_id(30), 1000, 5);
+        assert!(truncated);
+        assert_eq!(found.len(), 5);
+        let traffic: Vec<u64> = found
+            .iter()
+            .map(|x| x.iter().map(|x| edge_total(x)).min().unwrap())
+            .collect();
+        assert_eq!(traffic[0], 30);
+        assert!(traffic.windows(2).all(|x| x[0] >= x[1]));
+    }
+
+    #[test]
+    fn test_blast_radius() {
+        // 1 -> 2 -> 4 (target), 1 -> 3, 5 -> 2, 6 is unrelated
+        let g = graph(&[
+            (1, 2, 50, 0),
+            (1, 3, 50, 0),
+            (2, 4, 10, 0),
+            (2, 6, 30, 0),
+            (5, 2, 20, 0),
+            (6, 7, 5, 0),
+        ]);
+        let result = blast_radius(g, node_id(4));
+        let ids: Vec<u128> = result
+            .impacted
+            .iter()
+            .map(|x| x.node.node.node_id.as_u128())
+            .collect();
+        assert_eq!(ids, vec![2, 5, 1]);
+        assert!((result.impacted[0].share - 0.25).abs() < 1e-9);
+        assert!((result.impacted[1].share - 0.25).abs() < 1e-9);
+        assert!((result.impacted[2].share - 0.125).abs() < 1e-9);
+        assert_eq!(result.impacted[2].hops, 2);
+        assert_eq!(result.impacted[2].traffic, 13);
+
+        // all traffic of a cycle eventually reaches the target
+        let g = graph(&[(1, 2, 10, 0), (2, 1, 10, 0), (2, 3, 10, 0)]);
+        let result = blast_radius(g, node_id(3));
+        assert_eq!(result.impacted.len(), 2);
+        assert!(result.impacted.iter().all(|x| x.share > 0.99));
+    }
+
+    /// Fills in the inbound statuses of the nodes like `query_graph` does.
+    fn with_node_statuses(mut g: Graph) -> Graph {
+        for node in &mut g.nodes {
+            let node_id = node.node.node_id;
+            for edge in g.edges.iter().filter(|x| x.to_node_id == node_id) {
+                node.inbound.status_ok += edge.status_ok;
+                node.inbound.status_unexpected_error += edge.status_unexpected_error;
+            }
+        }
+        g
+    }
+
+    #[test]
+    fn test_root_causes() {
+        // 1 -> 2 -> 3 with 3 failing and 2 failing because of it, 1 -> 4 fails
+        // on its own but only a little
+        let g = with_node_statuses(graph(&[
+            (1, 2, 50, 50),
+            (2, 3, 40, 60),
+            (1, 4, 90, 10),
+            (4, 5, 100, 0),
+        ]));
+        let result = root_causes(g);
+        let ids: Vec<u128> = result
+            .candidates
+            .iter()
+            .map(|x| x.node.node.node_id.as_u128())
+            .collect();
+        assert_eq!(ids, vec![3, 2, 4]);
+        assert!((result.candidates[0].score - 0.6).abs() < 1e-9);
+        assert!(result.candidates[0]
+            .explanation
+            .contains("calls no other node"));
+        assert!(result.candidates[1].explanation.contains("service_3"));
+        assert!(result.candidates[2].explanation.contains("succeeded"));
+        // 60% of the errors of 2 come from 3
+        assert_eq!(result.candidates[0].attributed_errors, 90);
+        assert_eq!(result.candidates[1].attributed_errors, 20);
+
+        // 1 -> 2 -> 3 -> 4 where 3 only fails because of 4, the errors of 2
+        // are traced all the way down
+        let g = with_node_statuses(graph(&[
+            (1, 2, 50, 50),
+            (2, 3, 50, 50),
+            (3, 4, 0, 50),
+            (4, 5, 100, 0),
+        ]));
+        let result = root_causes(g);
+        assert_eq!(result.candidates[0].node.node.node_id, node_id(4));
+        assert_eq!(result.candidates[0].attributed_errors, 125);
+        let node_2 = result
+            .candidates
+            .iter()
+            .find(|x| x.node.node.node_id == node_id(2))
+            .unwrap();
+        assert!(node_2
+            .explanation
+            .contains("50.0% of its failures trace back to service_4"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut before = graph(&[(1, 2, 100, 0), (2, 3, 100, 0), (3, 4, 100, 0)]);
+        add_rates(&mut before, 60.0);
+        let mut after = graph(&[
+            (1, 2, 105, 0),
+            (2, 3, 90, 30),
+            (3, 4, 300, 0),
+            (2, 5, 10, 0),
+        ]);
+        add_rates(&mut after, 60.0);
+        let result = diff(before, after, 0.5, 0.05);
+        assert_eq!(result.added_nodes.len(), 1);
+        assert_eq!(result.added_nodes[0].node.node_id, node_id(5));
+        assert!(result.removed_nodes.is_empty());
+        assert_eq!(result.added_edges.len(), 1);
+        assert!(result.removed_edges.is_empty());
+        assert_eq!(result.changed_edges.len(), 2);
+        for change in &result.changed_edges {
+            if change.after.to_node_id == node_id(3) {
+                assert!((change.error_ratio_change - 0.25).abs() < 1e-9);
+            } else {
+                assert!((change.volume_change.unwrap() - 2.0).abs() < 1e-9);
+            }
+        }
+
+        // as many calls in half the time are twice the volume
+        let mut before = graph(&[(1, 2, 100, 0)]);
+        add_rates(&mut before, 60.0);
+        let mut after = graph(&[(1, 2, 100, 0)]);
+        add_rates(&mut after, 30.0);
+        let result = diff(before, after, 0.5, 0.05);
+        assert_eq!(result.changed_edges.len(), 1);
+        assert!((result.changed_edges[0].volume_change.unwrap() - 1.0).abs() < 1e-9);
+
+        let result = diff(graph(&[(1, 2, 1, 0)]), graph(&[]), 0.5, 0.05);
+        assert_eq!(result.removed_nodes.len(), 2);
+        assert_eq!(result.removed_edges.len(), 1);
+    }
+
+    /// A graph as reported by the SDK for services 1 and 2 with transactions
+    /// 11 and 21, where 11 calls 21 and an uninstrumented service 3.
+    fn reported_graph() -> Graph {
+        let mut g = graph(&[
+            (1, 2, 8, 2),
+            (1, 21, 8, 2),
+            (11, 2, 8, 2),
+            (11, 21, 8, 2),
+            (1, 3, 4, 0),
+            (11, 3, 4, 0),
+        ]);
+        for node in &mut g.nodes {
+            let id = node.node.node_id.as_u128();
+            if id >= 10 {
+                node.node.node_type = NodeType::Transaction;
+                node.node.parent_id = Some(node_id(id / 10));
+            }
+        }
+        g
+    }
+
+    fn ids(g: &Graph) -> Vec<u128> {
+        let mut rv: Vec<u128> = g.nodes.iter().map(|x| x.node.node_id.as_u128()).collect();
+        rv.sort();
+        rv
+    }
+
+    #[test]
+    fn test_apply_view() {
+        let g = apply_view(reported_graph(), GraphView::Service);
+        assert_eq!(ids(&g), vec![1, 2, 3]);
+        assert_eq!(g.edges.len(), 2);
+        let edge = g.edges.iter().find(|x| x.to_node_id == node_id(2)).unwrap();
+        assert_eq!(edge.from_node_id, node_id(1));
+        assert_eq!((edge.status_ok, edge.status_unexpected_error), (8, 2));
+        let service = g
+            .nodes
+            .iter()
+            .find(|x| x.node.node_id == node_id(2))
+            .unwrap();
+        assert_eq!(service.inbound.status_ok, 8);
+
+        let node = |g: &Graph, id: u128| {
+            g.nodes
+                .iter()
+                .find(|x| x.node.node_id == node_id(id))
+                .cloned()
+                .unwrap()
+        };
+
+        // statuses only count the calls of the view
+        let g = apply_view(reported_graph(), GraphView::Transaction);
+        assert_eq!(ids(&g), vec![3, 11, 21]);
+        assert_eq!(g.edges.len(), 2);
+        assert_eq!(node(&g, 21).inbound.status_ok, 8);
+        assert_eq!(node(&g, 11).outbound.status_ok, 12);
+
+        let g = apply_view(reported_graph(), GraphView::Mixed);
+        assert_eq!(ids(&g), vec![1, 2, 3, 11, 21]);
+        assert_eq!(g.edges.len(), 2);
+        assert!(g.edges.iter().all(|x| x.from_node_id == node_id(11)));
+        assert_eq!(node(&g, 1).outbound, StatusCounts::default());
+        assert_eq!(node(&g, 21).inbound.status_unexpected_error, 2);
+    }
+
+    #[test]
+    fn test_filter_traffic() {
+        let g = || {
+            graph(&[
+                (1, 2, 100, 0),
+                (2, 3, 50, 50),
+                (3, 4, 10, 0),
+                (4, 5, 5, 1),
+                (5, 6, 1, 0),
+            ])
+        };
+
+        let result = filter_traffic(
+            g(),
+            &TrafficFilter {
+                top_edges: Some(2),
+                ..Default::default()
+            },
+            60.0,
+        );
+        assert_eq!(result.edges.len(), 2);
+        assert_eq!(ids(&result), vec![1, 2, 3]);
+        // the calls from 3 to 4 were dropped
+        let node_3 = result
+            .nodes
+            .iter()
+            .find(|x| x.node.node_id == node_id(3))
+            .unwrap();
+        assert_eq!(node_3.inbound.status_ok, 50);
+        assert_eq!(node_3.outbound, StatusCounts::default());
+
+        let result = filter_traffic(
+            g(),
+            &TrafficFilter {
+                min_rpm: Some(0.1),
+                ..Default::default()
+            },
+            60.0,
+        );
+        assert_eq!(result.edges.len(), 4);
+
+        let result = filter_traffic(
+            g(),
+            &TrafficFilter {
+                min_error_ratio: Some(0.1),
+                ..Default::default()
+            },
+            60.0,
+        );
+        assert_eq!(result.edges.len(), 2);
+
+        let result = filter_traffic(
+            g(),
+            &TrafficFilter {
+                min_percentile: Some(50.0),
+                ..Default::default()
+            },
+            60.0,
+        );
+        assert_eq!(result.edges.len(), 3);
+
+        let result = filter_traffic(
+            g(),
+            &TrafficFilter {
+                traffic_volume: Some(50),
+                ..Default::default()
+            },
+            60.0,
+        );
+        assert_eq!(result.edges.len(), 2);
+
+        // equally busy edges all pass
+        let result = filter_traffic(
+            graph(&[(1, 2, 10, 0), (2, 3, 10, 0)]),
+            &TrafficFilter {
+                traffic_volume: Some(50),
+                min_percentile: Some(90.0),
+                ..Default::default()
+            },
+            60.0,
+        );
+        assert_eq!(result.edges.len(), 2);
+        assert_eq!(result.nodes.len(), 3);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let g = || graph(&[(1, 2, 1, 0), (2, 3, 100, 0), (3, 4, 10, 0), (5, 6, 50, 0)]);
+
+        let result = truncate(g(), 100, 100);
+        assert!(!result.truncated);
+        assert_eq!(result.edges.len(), 4);
+        assert_eq!(result.edges[0].to_node_id, node_id(3));
+
+        let result = truncate(g(), 100, 2);
+        assert!(result.truncated);
+        assert_eq!(ids(&result), vec![2, 3, 5, 6]);
+
+        // the edge 3 -> 4 would need a fifth node
+        let result = truncate(g(), 4, 100);
+        assert!(result.truncated);
+        assert_eq!(result.edges.len(), 2);
+        assert_eq!(ids(&result), vec![2, 3, 5, 6]);
+
+        // the mixed view keeps parents without edges, they stay with their
+        // transactions and count against the limit
+        let mut g = g();
+        for node in &mut g.nodes {
+            if node.node.node_id == node_id(3) {
+                node.node.node_type = NodeType::Transaction;
+                node.node.parent_id = Some(node_id(7));
+            }
+        }
+        let mut parent = g.nodes[0].clone();
+        parent.node.node_id = node_id(7);
+        g.nodes.push(parent);
+        let result = truncate(g, 4, 100);
+        assert!(result.truncated);
+        assert_eq!(ids(&result), vec![2, 3, 4, 7]);
+    }
+
+    #[test]
+    fn test_cycles() {
+        // services 1 -> 2 -> 3 -> 1, 3 -> 4, 4 -> 4 and transaction 11 of
+        // service 1 calling service 1
+        let mut g = graph(&[
+            (1, 2, 10, 0),
+            (2, 3, 5, 0),
+            (3, 1, 1, 0),
+            (3, 4, 7, 0),
+            (4, 4, 3, 0),
+            (11, 1, 2, 0),
+            (1, 11, 2, 0),
+        ]);
+        for node in &mut g.nodes {
+            if node.node.node_id == node_id(11) {
+                node.node.node_type = NodeType::Transaction;
+                node.node.parent_id = Some(node_id(1));
+            }
+        }
+
+        let result = cycles(
+            Graph {
+                edges: g.edges.clone(),
+                nodes: g.nodes.clone(),
+                truncated: false,
+            },
+            true,
+        );
+        assert_eq!(result.cycles.len(), 1);
+        let mut members: Vec<u128> = result.cycles[0]
+            .nodes
+            .iter()
+            .map(|x| x.node.node_id.as_u128())
+            .collect();
+        members.sort();
+        assert_eq!(members, vec![1, 2, 3]);
+        assert_eq!(result.cycles[0].edges.len(), 3);
+        assert_eq!(result.cycles[0].traffic, 16);
+
+        let result = cycles(g, false);
+        assert_eq!(result.cycles.len(), 2);
+        assert_eq!(result.cycles[0].nodes.len(), 3);
+        // the cycle through the transaction is folded into service 1
+        assert_eq!(result.cycles[0].edges.len(), 4);
+        assert_eq!(result.cycles[1].nodes[0].node.node_id, node_id(4));
+    }
+
+    #[test]
+    fn test_cycles_deep() {
+        // a chain far longer than recursion would allow, closed into a ring
+        let mut edges: Vec<(u128, u128, u64, u64)> =
+            (1..20_000).map(|x| (x, x + 1, 1, 0)).collect();
+        edges.push((20_000, 1, 1, 0));
+        let result = cycles(graph(&edges), true);
+        assert_eq!(result.cycles.len(), 1);
+        assert_eq!(result.cycles[0].nodes.len(), 20_000);
+    }
+
+    #[test]
+    fn test_centrality() {
+        // 1 -> 2 -> 3, 4 -> 2, 2 -> 5 with most calls going to 3
+        let mut g = graph(&[(1, 2, 10, 0), (4, 2, 10, 0), (2, 3, 90, 0), (2, 5, 10, 0)]);
+        add_centrality(&mut g);
+        let scores: HashMap<u128, Centrality> = g
+            .nodes
+            .iter()
+            .map(|x| (x.node.node_id.as_u128(), x.centrality.clone().unwrap()))
+            .collect();
+
+        assert_eq!(scores[&2].in_degree, 2);
+        assert_eq!(scores[&2].out_degree, 2);
+        // 4 of the 12 ordered pairs of other nodes go through 2
+        assert!((scores[&2].betweenness - 4.0 / 12.0).abs() < 1e-9);
+        assert_eq!(scores[&1].betweenness, 0.0);
+        assert!(scores[&3].pagerank > scores[&5].pagerank);
+        assert!(scores[&2].pagerank > scores[&1].pagerank);
+        let total: f64 = scores.values().map(|x| x.pagerank).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_add_rates() {
+        let mut g = with_node_statuses(graph(&[(1, 2, 90, 10), (2, 3, 0, 0)]));
+        add_rates(&mut g, 10.0);
+
+        let edge = &g.edges[0];
+        assert!((edge.rates.requests_per_minute - 10.0).abs() < 1e-9);
+        assert!((edge.rates.error_ratio - 0.1).abs() < 1e-9);
+        assert!((edge.rates.unexpected_error_ratio - 0.1).abs() < 1e-9);
+        // no calls is no errors rather than an undefined ratio
+        assert_eq!(g.edges[1].rates.error_ratio, 0.0);
+
+        let node = g
+            .nodes
+            .iter()
+            .find(|x| x.node.node_id == node_id(2))
+            .unwrap();
+        assert!((node.rates.requests_per_minute - 10.0).abs() < 1e-9);
+        assert!((node.rates.error_ratio - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_node_statuses() {
+        let mut g = reported_graph();
+        sum_node_statuses(&mut g);
+        let node = |g: &Graph, id| {
+            g.nodes
+                .iter()
+                .find(|x| x.node.node_id == node_id(id))
+                .cloned()
+        };
+
+        let caller = node(&g, 1).unwrap();
+        assert_eq!(caller.inbound, StatusCounts::default());
+        assert_eq!(caller.outbound.status_ok, 20);
+        assert_eq!(caller.outbound.status_unexpected_error, 4);
+        let callee = node(&g, 2).unwrap();
+        assert_eq!(callee.inbound.status_ok, 16);
+        assert_eq!(callee.outbound, StatusCounts::default());
+
+        // service 1 only reported its own calls to 2, its transactions call 3
+        let mut g = graph(&[(1, 2, 2, 0), (11, 3, 4, 1), (12, 3, 5, 0)]);
+        for node in &mut g.nodes {
+            let id = node.node.node_id.as_u128();
+            if id >= 10 {
+                node.node.node_type = NodeType::Transaction;
+                node.node.parent_id = Some(node_id(id / 10));
+            }
+        }
+        sum_node_statuses(&mut g);
+        roll_up_transactions(&mut g);
+        let service = node(&g, 1).unwrap();
+        assert_eq!(service.outbound.status_ok, 9);
+        assert_eq!(service.outbound.status_unexpected_error, 1);
+        assert_eq!(node(&g, 11).unwrap().outbound.status_ok, 4);
+        assert_eq!(node(&g, 3).unwrap().inbound.status_ok, 9);
+    }
+
+    #[test]
+    fn test_sampled_counts() {
+        // 5000 calls at the smallest sample rate, upscaled and saturated per
+        // row like edges_by_minute does
+        let sample_rate = crate::payloads::MIN_SAMPLE_RATE;
+        let upscaled = (5000.0 / sample_rate).round().min(u32::MAX as f64) as u64;
+        assert_eq!(upscaled, u32::MAX as u64);
+
+        // two sampled transactions of service 1 calling 2, service 1 itself
+        // only reported a call to 3
+        let mut g = graph(&[
+            (1, 3, 1, 0),
+            (11, 2, upscaled, 0),
+            (12, 2, upscaled, upscaled),
+        ]);
+        for node in &mut g.nodes {
+            let id = node.node.node_id.as_u128();
+            if id >= 10 {
+                node.node.node_type = NodeType::Transaction;
+                node.node.parent_id = Some(node_id(id / 10));
+            }
+        }
+        sum_node_statuses(&mut g);
+        let callee = g
+            .nodes
+            .iter()
+            .find(|x| x.node.node_id == node_id(2))
+            .unwrap();
+        assert_eq!(callee.inbound.status_ok, 2 * upscaled);
+        assert_eq!(callee.inbound.status_unexpected_error, upscaled);
+
+        let g = apply_view(g, GraphView::Service);
+        let edge = g.edges.iter().find(|x| x.to_node_id == node_id(2)).unwrap();
+        assert_eq!(edge.status_ok, 2 * upscaled);
+        let service = g
+            .nodes
+            .iter()
+            .find(|x| x.node.node_id == node_id(1))
+            .unwrap();
+        assert_eq!(service.outbound.status_ok, 2 * upscaled + 1);
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use chrono::Utc;
+use clickhouse_rs::ClientHandle;
+use serde_json::Value;
+
+use crate::db::{get_client, register_submission};
+use crate::error::Error;
+use crate::payloads::{Edge, ImportLineError, ImportReport, Node, SubmitData};
+use crate::timestamps::TimestampConfig;
+
+/// Maximum number of per-line errors kept in a report.
+const MAX_REPORTED_ERRORS: usize = 1000;
+
+pub const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+enum Record {
+    Submission(SubmitData),
+    Node(u64, Node),
+    Edge(u64, Edge),
+}
+
+fn parse_record(line: &str, default_project_id: Option<u64>) -> Result<Record, Error> {
+    let value: Value = serde_json::from_str(line)?;
+    let object = value
+        .as_object()
+        .ok_or_else(|| anyhow::anyhow!("expected a JSON object"))?;
+    let project_id = match object.get("project_id") {
+        Some(project_id) => Some(
+            project_id
+                .as_u64()
+                .ok_or_else(|| anyhow::anyhow!("invalid project_id"))?,
+        ),
+        None => default_project_id,
+    };
+
+    // bare records are told apart by their id fields
+    let record = if object.contains_key("from_node_id") {
+        let edge: Edge = serde_json::from_value(value)?;
+        edge.validate()?;
+        Record::Edge(missing_project_id(project_id)?, edge)
+    } else if object.contains_key("node_id") {
+        let node: Node = serde_json::from_value(value)?;
+        node.validate()?;
+        Record::Node(missing_project_id(project_id)?, node)
+    } else {
+        let data: SubmitData = serde_json::from_value(value)?;
+        data.validate()?;
+        // unlike `/submit`, imports only take complete hierarchies
+        for node in &data.nodes {
+            node.validate()?;
+        }
+        Record::Submission(data)
+    };
+    Ok(record)
+}
+
+fn missing_project_id(project_id: Option<u64>) -> Result<u64, Error> {
+    project_id.ok_or_else(|| anyhow::anyhow!("record has no project_id and no default was given"))
+}
+
+/// Loads newline delimited submissions, nodes and edges in batches.
+pub struct Importer {
+    default_project_id: Option<u64>,
+    batch_size: usize,
+    timestamps: TimestampConfig,
+    pending: BTreeMap<u64, SubmitData>,
+    pending_rows: usize,
+    report: ImportReport,
+}
+
+impl Importer {
+    pub fn new(
+        default_project_id: Option<u64>,
+        batch_size: usize,
+        timestamps: TimestampConfig,
+    ) -> Importer {
+        Importer {
+            default_project_id,
+            batch_size: batch_size.max(1),
+            timestamps,
+            pending: BTreeMap::new(),
+            pending_rows: 0,
+            report: ImportReport::default(),
+        }
+    }
+
+    pub fn report(&self) -> &ImportReport {
+        &self.report
+    }
+
+    /// Returns the final report, recording the error the import stopped at.
+    pub fn finish(self, result: Result<(), Error>) -> ImportReport {
+        let mut report = self.report;
+        if let Err(err) = result {
+            report.error = Some(err.to_string());
+        }
+        report
+    }
+
+    /// Adds one line of input, writing a batch once enough rows are pending.
+    ///
+    /// Lines that cannot be parsed are recorded in the report and skipped,
+    /// only database errors are returned.
+    pub async fn feed_line(&mut self, client: &mut ClientHandle, line: &str) -> Result<(), Error> {
+        self.report.lines += 1;
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        match parse_record(line, self.default_project_id) {
+            Ok(record) => self.add(record),
+            Err(err) => {
+                self.report.error_count += 1;
+                if self.report.errors.len() < MAX_REPORTED_ERRORS {
+                    self.report.errors.push(ImportLineError {
+                        line: self.report.lines,
+                        error: err.to_string(),
+                    });
+                }
+            }
+        }
+
+        if self.pending_rows >= self.batch_size {
+            self.flush(client).await?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, record: Record) {
+        let (project_id, nodes, edges) = match record {
+            Record::Submission(data) => (data.project_id, data.nodes, data.edges),
+            Record::Node(project_id, node) => (project_id, vec![node], vec![]),
+            Record::Edge(project_id, edge) => (project_id, vec![], vec![edge]),
+        };
+        self.pending_rows += nodes.len() + edges.len();
+        let pending = self
+            .pending
+            .entry(project_id)
+            .or_insert_with(|| SubmitData {
+                project_id,
+                ..Default::default()
+            });
+        pending.nodes.extend(nodes);
+        pending.edges.extend(edges);
+    }
+
+    /// Writes all pending rows, their edges go through the timestamp policy
+    /// like submitted ones.
+    pub async fn flush(&mut self, client: &mut ClientHandle) -> Result<(), Error> {
+        for (_, data) in std::mem::take(&mut self.pending) {
+            let (data, decisions) = self.timestamps.apply(Utc::now(), data);
+            register_submission(client, &data).await?;
+            self.report.nodes += data.data.nodes.len();
+            self.report.edges += data.data.edges.len() + data.backfill_edges.len();
+            self.report.timestamps.add(decisions);
+            self.report.batches += 1;
+        }
+        self.pending_rows = 0;
+        Ok(())
+    }
+}
+
+fn usage() -> Error {
+    anyhow::anyhow!("usage: servicegraph-api import [--project-id ID] [--batch-size N] <FILE|->")
+}
+
+/// Entry point of the `import` subcommand.
+pub async fn run_cli(args: &[String]) -> Result<(), Error> {
+    let mut project_id = None;
+    let mut batch_size = DEFAULT_BATCH_SIZE;
+    let mut path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--project-id" => project_id = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--batch-size" => batch_size = args.next().ok_or_else(usage)?.parse()?,
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(usage()),
+        }
+    }
+
+    let reader: Box<dyn BufRead + Send> = match path.as_deref() {
+        Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => return Err(usage()),
+    };
+
+    let timestamps: TimestampConfig = rocket::Config::figment().extract()?;
+    let mut importer = Importer::new(project_id, batch_size, timestamps);
+    let result = import_lines(&mut importer, reader).await;
+    let report = importer.finish(result);
+
+    for error in &report.errors {
+        eprintln!("line {}: {}", error.line, error.error);
+    }
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    match report.error {
+        Some(error) => Err(anyhow::anyhow!("import stopped: {}", error)),
+        None => Ok(()),
+    }
+}
+
+async fn import_lines(importer: &mut Importer, reader: impl BufRead) -> Result<(), Error> {
+    let mut client = get_client().await?;
+    let mut last_batches = 0;
+    for line in reader.lines() {
+        importer.feed_line(&mut client, &line?).await?;
+        let report = importer.report();
+        if report.batches != last_batches {
+            last_batches = report.batches;
+            eprintln!(
+                "line {}: {} nodes, {} edges imported, {} errors",
+                report.lines, report.nodes, report.edges, report.error_count
+            );
+        }
+    }
+    importer.flush(&mut client).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_records() {
+        let edge = r#"{"ts": "2021-06-09T00:00:00Z", "from_node_id": "418f3d00-ba14-42eb-98b8-5f3fb1b975c8", "to_node_id": "5042546b-07a0-41d4-a73c-9138722eebb4", "status": "ok", "n": 3, "description": null, "class": null}"#;
+        let node = r#"{"project_id": 7, "node_id": "418f3d00-ba14-42eb-98b8-5f3fb1b975c8", "node_type": "service", "name": "ServiceA", "description": null, "class": null, "parent_id": null}"#;
+        let submission = r#"{"project_id": 42, "edges": []}"#;
+
+        assert!(matches!(
+            parse_record(edge, Some(1)),
+            Ok(Record::Edge(1, _))
+        ));
+        assert!(parse_record(edge, None).is_err());
+        assert!(matches!(parse_record(node, None), Ok(Record::Node(7, _))));
+        assert!(matches!(
+            parse_record(submission, None),
+            Ok(Record::Submission(SubmitData { project_id: 42, .. }))
+        ));
+        assert!(parse_record("[1, 2]", Some(1)).is_err());
+        assert!(parse_record(r#"{"project_id": 1, "edges": [{}]}"#, None).is_err());
+        let orphan = r#"{"project_id": 1, "nodes": [{"node_id": "418f3d00-ba14-42eb-98b8-5f3fb1b975c8", "node_type": "transaction", "name": "/", "description": null, "class": null, "parent_id": null}]}"#;
+        assert!(parse_record(orphan, None).is_err());
+    }
+}
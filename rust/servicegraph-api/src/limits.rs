@@ -0,0 +1,76 @@
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+/// Caps on the size of query responses (read from `Rocket.toml`).
+#[derive(Deserialize, Debug, Clone)]
+pub struct ResponseLimits {
+    /// Most nodes returned in a graph, the quietest parts are dropped first.
+    #[serde(default = "default_max_graph_nodes")]
+    pub max_graph_nodes: usize,
+    /// Most edges returned in a graph, the quietest ones are dropped first.
+    #[serde(default = "default_max_graph_edges")]
+    pub max_graph_edges: usize,
+    /// Largest graph centrality scores are computed for, counted before the
+    /// graph is capped at `max_graph_nodes`.
+    #[serde(default = "default_max_centrality_nodes")]
+    pub max_centrality_nodes: usize,
+    /// Largest page of a paginated listing.
+    #[serde(default = "default_max_page_size")]
+    pub max_page_size: usize,
+    /// Most buckets in a histogram.
+    #[serde(default = "default_max_histogram_buckets")]
+    pub max_histogram_buckets: usize,
+}
+
+fn default_max_graph_nodes() -> usize {
+    2000
+}
+
+fn default_max_graph_edges() -> usize {
+    5000
+}
+
+fn default_max_centrality_nodes() -> usize {
+    20000
+}
+
+fn default_max_page_size() -> usize {
+    1000
+}
+
+fn default_max_histogram_buckets() -> usize {
+    1500
+}
+
+impl Default for ResponseLimits {
+    fn default() -> ResponseLimits {
+        ResponseLimits {
+            max_graph_nodes: default_max_graph_nodes(),
+            max_graph_edges: default_max_graph_edges(),
+            max_centrality_nodes: default_max_centrality_nodes(),
+            max_page_size: default_max_page_size(),
+            max_histogram_buckets: default_max_histogram_buckets(),
+        }
+    }
+}
+
+impl ResponseLimits {
+    /// Size of a page given the requested size.
+    pub fn page_size(&self, requested: Option<usize>) -> usize {
+        requested
+            .unwrap_or(self.max_page_size)
+            .clamp(1, self.max_page_size.max(1))
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Response limits", |rocket| async {
+        match rocket.figment().extract::<ResponseLimits>() {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(err) => {
+                error!("invalid response limits: {}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
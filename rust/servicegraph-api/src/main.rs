@@ -2,22 +2,40 @@ mod payloads;
 
 #[macro_use]
 extern crate rocket;
+mod codec;
+mod dates;
 mod db;
+mod dedup;
 mod endpoints;
 mod error;
+mod graph;
+mod import;
+mod limits;
+mod proto;
+mod query;
+mod spool;
+mod timestamps;
+
+use std::env;
+use std::process;
 
 use rocket::http::Method;
+use rocket::{Build, Rocket};
 use rocket_cors::{AllowedHeaders, AllowedOrigins, CorsOptions};
 
-#[launch]
-fn rocket() -> _ {
+fn rocket() -> Rocket<Build> {
     let cors = CorsOptions {
         allowed_origins: AllowedOrigins::all(),
         allowed_methods: vec![Method::Get, Method::Post, Method::Options]
             .into_iter()
             .map(From::from)
             .collect(),
-        allowed_headers: AllowedHeaders::some(&["Authorization", "Accept", "Content-Type"]),
+        allowed_headers: AllowedHeaders::some(&[
+            "Authorization",
+            "Accept",
+            "Content-Type",
+            "Content-Encoding",
+        ]),
         allow_credentials: true,
         ..Default::default()
     }
@@ -29,14 +47,51 @@ fn rocket() -> _ {
             "/api/",
             routes![
                 endpoints::query_graph,
+                endpoints::get_graph,
                 endpoints::query_active_nodes,
+                endpoints::get_active_nodes,
                 endpoints::query_histogram,
+                endpoints::get_histogram,
                 endpoints::query_service_map,
+                endpoints::get_service_map,
+                endpoints::query_neighborhood,
+                endpoints::get_neighborhood,
+                endpoints::query_paths,
+                endpoints::get_paths,
+                endpoints::query_blast_radius,
+                endpoints::get_blast_radius,
+                endpoints::query_cycles,
+                endpoints::get_cycles,
+                endpoints::query_root_causes,
+                endpoints::get_root_causes,
+                endpoints::query_graph_diff,
+                endpoints::get_graph_diff,
+                endpoints::query_node_search,
+                endpoints::get_node_search,
+                endpoints::query_node,
+                endpoints::get_node,
                 endpoints::health
             ],
         )
-        .mount("/", routes![endpoints::submit])
+        .mount("/", routes![endpoints::submit, endpoints::import])
         .mount("/", rocket_cors::catch_all_options_routes())
+        .attach(spool::SpoolFairing)
+        .attach(dedup::fairing())
+        .attach(timestamps::fairing())
+        .attach(limits::fairing())
         .attach(cors.clone())
         .manage(cors)
 }
+
+#[rocket::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("import") => import::run_cli(&args[2..]).await,
+        _ => rocket().launch().await.map_err(From::from),
+    };
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
@@ -1,15 +1,26 @@
 use std::collections::BTreeSet;
 use std::ops::Deref;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::dates::{DateSpec, Granularity};
+use crate::error::Error;
+use crate::query;
+
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct CommonQueryParams {
+    #[serde(deserialize_with = "query::from_str")]
     pub project_id: u64,
-    pub start_date: Option<DateTime<Utc>>,
-    pub end_date: Option<DateTime<Utc>>,
+    /// Defaults to an hour ago.
+    pub start_date: Option<DateSpec>,
+    /// Defaults to now.
+    pub end_date: Option<DateSpec>,
+    /// IANA name of the viewer's timezone, for `today` and `yesterday` and
+    /// the hour and day buckets of histograms.  Defaults to UTC.
+    pub timezone: Option<Tz>,
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -22,6 +33,60 @@ pub struct GraphQueryParams {
     pub to_types: BTreeSet<NodeType>,
     #[serde(default)]
     pub edge_statuses: BTreeSet<EdgeStatus>,
+    #[serde(flatten)]
+    pub attributes: AttributeFilter,
+    #[serde(flatten)]
+    pub traffic: TrafficFilter,
+    /// Return centrality scores with every node.
+    #[serde(default, deserialize_with = "query::from_str")]
+    pub with_centrality: bool,
+    /// Include the statuses of transactions in those of their services.
+    #[serde(default, deserialize_with = "query::from_str")]
+    pub roll_up_transactions: bool,
+}
+
+/// Restricts edges by the names and classes of their nodes and their own
+/// class and description.  Applied inside the database query.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct AttributeFilter {
+    /// Name of either node of the edge, a prefix or a glob with `*` and `?`.
+    pub node_name: Option<String>,
+    /// Class of either node of the edge.
+    pub node_class: Option<String>,
+    pub edge_class: Option<String>,
+    /// Substring of the edge description.
+    pub edge_description: Option<String>,
+}
+
+/// Drops edges by their traffic, nodes left without edges are dropped too.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct TrafficFilter {
+    /// Keep only this many edges, the busiest ones.
+    #[serde(default, deserialize_with = "query::option_from_str")]
+    pub top_edges: Option<usize>,
+    /// Minimum calls per minute over the queried window.
+    #[serde(default, deserialize_with = "query::option_from_str")]
+    pub min_rpm: Option<f64>,
+    /// Minimum fraction of calls failing unexpectedly.
+    #[serde(default, deserialize_with = "query::option_from_str")]
+    pub min_error_ratio: Option<f64>,
+    /// Keep only edges at or above this percentile (0-100) of edge volumes.
+    #[serde(default, deserialize_with = "query::option_from_str")]
+    pub min_percentile: Option<f64>,
+    /// Keep only edges at or above this percentage (0-100) of the range
+    /// between the quietest and the busiest edge.
+    #[serde(default, deserialize_with = "query::option_from_str")]
+    pub traffic_volume: Option<u32>,
+}
+
+impl TrafficFilter {
+    pub fn is_empty(&self) -> bool {
+        self.top_edges.is_none()
+            && self.min_rpm.is_none()
+            && self.min_error_ratio.is_none()
+            && self.min_percentile.is_none()
+            && self.traffic_volume.is_none()
+    }
 }
 
 impl Deref for GraphQueryParams {
@@ -32,12 +97,219 @@ impl Deref for GraphQueryParams {
     }
 }
 
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct NeighborhoodQueryParams {
+    #[serde(flatten)]
+    pub graph: GraphQueryParams,
+    /// The node the neighborhood is centered on.
+    pub node_id: Uuid,
+    /// How many hops of callers to include.
+    #[serde(default = "default_hops", deserialize_with = "query::from_str")]
+    pub upstream_hops: u32,
+    /// How many hops of callees to include.
+    #[serde(default = "default_hops", deserialize_with = "query::from_str")]
+    pub downstream_hops: u32,
+}
+
+fn default_hops() -> u32 {
+    1
+}
+
+impl Deref for NeighborhoodQueryParams {
+    type Target = GraphQueryParams;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct PathQueryParams {
+    #[serde(flatten)]
+    pub graph: GraphQueryParams,
+    pub from_node_id: Uuid,
+    pub to_node_id: Uuid,
+    /// Only return this many paths, the ones carrying the most traffic.
+    #[serde(default, deserialize_with = "query::option_from_str")]
+    pub limit: Option<usize>,
+    /// Longest path to consider, in edges, at most 16.
+    #[serde(
+        default = "default_max_path_hops",
+        deserialize_with = "query::from_str"
+    )]
+    pub max_hops: u32,
+}
+
+fn default_max_path_hops() -> u32 {
+    8
+}
+
+impl Deref for PathQueryParams {
+    type Target = GraphQueryParams;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ImpactQueryParams {
+    #[serde(flatten)]
+    pub graph: GraphQueryParams,
+    /// The node whose callers are looked for.
+    pub node_id: Uuid,
+}
+
+impl Deref for ImpactQueryParams {
+    type Target = GraphQueryParams;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct GraphDiffQueryParams {
+    /// Both windows are queried with these filters, `start_date` and
+    /// `end_date` are the defaults of the "after" window.
+    #[serde(flatten)]
+    pub graph: GraphQueryParams,
+    /// Defaults to a window as long as the "after" one, right before it.
+    pub before_start: Option<DateSpec>,
+    pub before_end: Option<DateSpec>,
+    /// Defaults to the last hour.
+    pub after_start: Option<DateSpec>,
+    pub after_end: Option<DateSpec>,
+    /// Relative change of an edge's call count that is reported.
+    #[serde(
+        default = "default_volume_threshold",
+        deserialize_with = "query::from_str"
+    )]
+    pub volume_threshold: f64,
+    /// Change of an edge's unexpected error ratio that is reported.
+    #[serde(
+        default = "default_error_ratio_threshold",
+        deserialize_with = "query::from_str"
+    )]
+    pub error_ratio_threshold: f64,
+}
+
+fn default_volume_threshold() -> f64 {
+    0.5
+}
+
+fn default_error_ratio_threshold() -> f64 {
+    0.05
+}
+
+impl Deref for GraphDiffQueryParams {
+    type Target = GraphQueryParams;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
+
+impl GraphDiffQueryParams {
+    fn window(&self, start_date: DateTime<Utc>, end_date: DateTime<Utc>) -> GraphQueryParams {
+        let mut params = self.graph.clone();
+        params.common.start_date = Some(start_date.into());
+        params.common.end_date = Some(end_date.into());
+        params
+    }
+
+    /// Returns the graph queries for the before and after windows.
+    pub fn windows(&self) -> (GraphQueryParams, GraphQueryParams) {
+        self.windows_at(Utc::now())
+    }
+
+    /// Like `windows` with relative dates resolved at `now`.
+    pub fn windows_at(&self, now: DateTime<Utc>) -> (GraphQueryParams, GraphQueryParams) {
+        let tz = self.timezone.unwrap_or(Tz::UTC);
+        let resolve = |date: Option<DateSpec>| date.map(|x| x.resolve(now, tz));
+        let after_end = resolve(self.after_end)
+            .or_else(|| resolve(self.end_date))
+            .unwrap_or(now);
+        let after_start = resolve(self.after_start)
+            .or_else(|| resolve(self.start_date))
+            .unwrap_or_else(|| after_end - Duration::hours(1));
+        let before_end = resolve(self.before_end).unwrap_or(after_start);
+        let before_start =
+            resolve(self.before_start).unwrap_or_else(|| before_end - (after_end - after_start));
+        (
+            self.window(before_start, before_end),
+            self.window(after_start, after_end),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct NodeSearchParams {
+    #[serde(flatten)]
+    pub common: CommonQueryParams,
+    /// Matched against the start of node names, then anywhere in names and
+    /// descriptions and finally fuzzily against names.
+    pub q: String,
+    #[serde(default)]
+    pub types: BTreeSet<NodeType>,
+    /// At most `max_page_size`.
+    #[serde(default = "default_search_limit", deserialize_with = "query::from_str")]
+    pub limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+impl Deref for NodeSearchParams {
+    type Target = CommonQueryParams;
+
+    fn deref(&self) -> &Self::Target {
+        &self.common
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CycleQueryParams {
+    #[serde(flatten)]
+    pub graph: GraphQueryParams,
+    /// Don't report services calling themselves (or their own transactions).
+    #[serde(default = "default_true", deserialize_with = "query::from_str")]
+    pub ignore_self_loops: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for CycleQueryParams {
+    fn default() -> CycleQueryParams {
+        CycleQueryParams {
+            graph: GraphQueryParams::default(),
+            ignore_self_loops: default_true(),
+        }
+    }
+}
+
+impl Deref for CycleQueryParams {
+    type Target = GraphQueryParams;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct NodeQueryParams {
     #[serde(flatten)]
     pub common: CommonQueryParams,
     #[serde(default)]
     pub types: BTreeSet<NodeType>,
+    /// The `next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<Uuid>,
+    #[serde(default, deserialize_with = "query::option_from_str")]
+    pub limit: Option<usize>,
 }
 
 impl Deref for NodeQueryParams {
@@ -48,6 +320,35 @@ impl Deref for NodeQueryParams {
     }
 }
 
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct HistogramQueryParams {
+    #[serde(flatten)]
+    pub common: CommonQueryParams,
+    /// Only count edges going into one of these nodes.
+    #[serde(default)]
+    pub node_ids: BTreeSet<Uuid>,
+    /// Only count edges starting at this node.
+    #[serde(default)]
+    pub from_node_id: Option<Uuid>,
+    /// Only count edges ending at this node.
+    #[serde(default)]
+    pub to_node_id: Option<Uuid>,
+    /// Only count edges going into nodes of these types.
+    #[serde(default)]
+    pub node_types: BTreeSet<NodeType>,
+    /// Width of the buckets, picked from the length of the range if unset.
+    #[serde(default)]
+    pub granularity: Option<Granularity>,
+}
+
+impl Deref for HistogramQueryParams {
+    type Target = CommonQueryParams;
+
+    fn deref(&self) -> &Self::Target {
+        &self.common
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, PartialEq, Eq, Ord, PartialOrd)]
 #[serde(rename_all = "snake_case")]
 pub enum EdgeStatus {
@@ -84,6 +385,144 @@ pub struct Edge {
     pub n: u32,
     pub description: Option<String>,
     pub class: Option<String>,
+    /// The fraction of calls that were reported, `n` is upscaled by its inverse.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+/// Smallest `sample_rate` accepted, smaller ones would not survive being
+/// stored as `Float32` and upscale counts beyond what a `UInt32` holds.
+pub const MIN_SAMPLE_RATE: f64 = 1e-6;
+
+impl Edge {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(self.sample_rate >= MIN_SAMPLE_RATE && self.sample_rate <= 1.0) {
+            return Err(anyhow::anyhow!(
+                "invalid sample_rate {} (must be in [{}, 1])",
+                self.sample_rate,
+                MIN_SAMPLE_RATE
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SubmitData {
+    pub project_id: u64,
+    /// Client chosen id of this batch; resubmitting it is a no-op.
+    #[serde(default)]
+    pub batch_id: Option<String>,
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+    #[serde(default)]
+    pub edges: Vec<Edge>,
+}
+
+impl SubmitData {
+    /// Rejects edges that cannot be stored.
+    pub fn validate(&self) -> Result<(), Error> {
+        for edge in &self.edges {
+            edge.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// A submission after the timestamp policy sorted its edges, as it is
+/// written to the database and the spool.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StoredSubmission {
+    #[serde(flatten)]
+    pub data: SubmitData,
+    /// Late edges that are written to the backfill table.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backfill_edges: Vec<Edge>,
+}
+
+impl From<SubmitData> for StoredSubmission {
+    fn from(data: SubmitData) -> StoredSubmission {
+        StoredSubmission {
+            data,
+            backfill_edges: vec![],
+        }
+    }
+}
+
+/// How the edges of a submission were treated by the timestamp policy.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone)]
+pub struct TimestampDecisions {
+    pub accepted: usize,
+    pub clamped: usize,
+    pub rejected_future: usize,
+    pub rejected_late: usize,
+    pub backfilled: usize,
+}
+
+impl TimestampDecisions {
+    pub fn add(&mut self, other: TimestampDecisions) {
+        self.accepted += other.accepted;
+        self.clamped += other.clamped;
+        self.rejected_future += other.rejected_future;
+        self.rejected_late += other.rejected_late;
+        self.backfilled += other.backfilled;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SubmitResponse {
+    pub batch_id: Option<String>,
+    pub nodes: usize,
+    pub edges: usize,
+    /// The batch was written to the spool and will be stored later.
+    pub spooled: bool,
+    /// The batch was seen before and was not stored again.
+    pub duplicate: bool,
+    pub timestamps: TimestampDecisions,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportLineError {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ImportReport {
+    pub lines: usize,
+    /// Nodes and edges written so far, edges after the timestamp policy.
+    pub nodes: usize,
+    pub edges: usize,
+    pub batches: usize,
+    pub timestamps: TimestampDecisions,
+    pub error_count: usize,
+    /// The first errors encountered, the rest are only counted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ImportLineError>,
+    /// The database error the import stopped at, rows not yet written by then
+    /// are lost.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ImportReport {
+    /// The counts of the report, without the line errors.
+    pub fn progress(&self) -> ImportReport {
+        ImportReport {
+            lines: self.lines,
+            nodes: self.nodes,
+            edges: self.edges,
+            batches: self.batches,
+            timestamps: self.timestamps,
+            error_count: self.error_count,
+            errors: Vec::new(),
+            error: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -92,9 +531,37 @@ pub struct CombinedEdge {
     pub to_node_id: Uuid,
     pub description: Option<String>,
     pub class: Option<String>,
-    pub status_ok: u32,
-    pub status_expected_error: u32,
-    pub status_unexpected_error: u32,
+    pub status_ok: u64,
+    pub status_expected_error: u64,
+    pub status_unexpected_error: u64,
+    /// The counts were extrapolated from sampled edges.
+    pub extrapolated: bool,
+    #[serde(flatten)]
+    pub rates: Rates,
+}
+
+/// The status counts relative to the length of the queried window.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+#[serde(default)]
+pub struct Rates {
+    pub requests_per_minute: f64,
+    /// Share of calls that failed, expected or not.
+    pub error_ratio: f64,
+    pub unexpected_error_ratio: f64,
+}
+
+impl Rates {
+    pub fn new(ok: u64, expected_error: u64, unexpected_error: u64, minutes: f64) -> Rates {
+        let total = ok as f64 + expected_error as f64 + unexpected_error as f64;
+        if total == 0.0 {
+            return Rates::default();
+        }
+        Rates {
+            requests_per_minute: total / minutes.max(1.0),
+            error_ratio: (expected_error as f64 + unexpected_error as f64) / total,
+            unexpected_error_ratio: unexpected_error as f64 / total,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
@@ -120,7 +587,7 @@ impl NodeType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Node {
     pub node_id: Uuid,
     pub node_type: NodeType,
@@ -130,6 +597,18 @@ pub struct Node {
     pub parent_id: Option<Uuid>,
 }
 
+impl Node {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.node_type == NodeType::Transaction && self.parent_id.is_none() {
+            return Err(anyhow::anyhow!(
+                "transaction {} has no parent service",
+                self.node_id
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NodeActivity {
     #[serde(flatten)]
@@ -138,23 +617,243 @@ pub struct NodeActivity {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct NodeWithStatus {
+pub struct NodeSearchResult {
     #[serde(flatten)]
     pub node: Node,
-    pub status_ok: u32,
-    pub status_expected_error: u32,
-    pub status_unexpected_error: u32,
+    /// Name of the service a transaction belongs to.
+    pub parent_name: Option<String>,
+    /// Last edge from or to the node within the queried window.
+    pub last_activity: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeSearchResults {
+    /// Best matches first, ties broken by recent activity.
+    pub nodes: Vec<NodeSearchResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "NodeWithStatusFields", into = "NodeWithStatusFields")]
+pub struct NodeWithStatus {
+    pub node: Node,
+    /// Calls received by the node.
+    pub inbound: StatusCounts,
+    /// Calls made by the node.
+    pub outbound: StatusCounts,
+    /// The counts were extrapolated from sampled edges.
+    pub extrapolated: bool,
+    pub rates: Rates,
+    pub centrality: Option<Centrality>,
+}
+
+/// How a `NodeWithStatus` is serialized, the inbound counts are repeated as
+/// the top level `status_*` fields that predate `inbound`.
+#[derive(Serialize, Deserialize)]
+struct NodeWithStatusFields {
+    #[serde(flatten)]
+    node: Node,
+    #[serde(flatten)]
+    status: StatusCounts,
+    #[serde(default, skip_deserializing)]
+    inbound: StatusCounts,
+    #[serde(default)]
+    outbound: StatusCounts,
+    extrapolated: bool,
+    #[serde(flatten)]
+    rates: Rates,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    centrality: Option<Centrality>,
+}
+
+impl From<NodeWithStatusFields> for NodeWithStatus {
+    fn from(fields: NodeWithStatusFields) -> NodeWithStatus {
+        NodeWithStatus {
+            node: fields.node,
+            inbound: fields.status,
+            outbound: fields.outbound,
+            extrapolated: fields.extrapolated,
+            rates: fields.rates,
+            centrality: fields.centrality,
+        }
+    }
+}
+
+impl From<NodeWithStatus> for NodeWithStatusFields {
+    fn from(node: NodeWithStatus) -> NodeWithStatusFields {
+        NodeWithStatusFields {
+            node: node.node,
+            status: node.inbound,
+            inbound: node.inbound,
+            outbound: node.outbound,
+            extrapolated: node.extrapolated,
+            rates: node.rates,
+            centrality: node.centrality,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq)]
+pub struct StatusCounts {
+    pub status_ok: u64,
+    pub status_expected_error: u64,
+    pub status_unexpected_error: u64,
+}
+
+impl StatusCounts {
+    pub fn of_edge(edge: &CombinedEdge) -> StatusCounts {
+        StatusCounts {
+            status_ok: edge.status_ok,
+            status_expected_error: edge.status_expected_error,
+            status_unexpected_error: edge.status_unexpected_error,
+        }
+    }
+
+    pub fn add(&mut self, other: StatusCounts) {
+        self.status_ok += other.status_ok;
+        self.status_expected_error += other.status_expected_error;
+        self.status_unexpected_error += other.status_unexpected_error;
+    }
+
+    pub fn add_edge(&mut self, edge: &CombinedEdge) {
+        self.add(StatusCounts::of_edge(edge));
+    }
+
+    /// The larger of each count.
+    pub fn max(self, other: StatusCounts) -> StatusCounts {
+        StatusCounts {
+            status_ok: self.status_ok.max(other.status_ok),
+            status_expected_error: self.status_expected_error.max(other.status_expected_error),
+            status_unexpected_error: self
+                .status_unexpected_error
+                .max(other.status_unexpected_error),
+        }
+    }
+}
+
+/// How central a node is to the graph it was returned in.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Centrality {
+    /// Number of nodes calling this node.
+    pub in_degree: usize,
+    /// Number of nodes this node calls.
+    pub out_degree: usize,
+    /// Fraction of shortest paths between other nodes passing through this
+    /// node.
+    pub betweenness: f64,
+    /// PageRank with edges weighted by their number of calls.
+    pub pagerank: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Graph {
+    /// Busiest first where the graph was limited in size.
     pub edges: Vec<CombinedEdge>,
     pub nodes: Vec<NodeWithStatus>,
+    /// Edges (and the nodes only they connect) were dropped to stay within
+    /// the response limits.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Path {
+    /// Calls on the quietest hop, the most traffic that can have taken the
+    /// whole path.
+    pub traffic: u64,
+    pub hops: Vec<CombinedEdge>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Paths {
+    pub paths: Vec<Path>,
+    /// The nodes along the returned paths.
+    pub nodes: Vec<NodeWithStatus>,
+    /// More paths may exist than were returned.
+    pub truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImpactedNode {
+    pub node: NodeWithStatus,
+    /// Length of the shortest call chain to the failing node.
+    pub hops: u32,
+    /// Fraction of the node's outgoing calls that end up at the failing node.
+    pub share: f64,
+    /// Outgoing calls of the node that end up at the failing node.
+    pub traffic: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlastRadius {
+    pub node_id: Uuid,
+    /// Nodes depending on `node_id`, most dependent first.
+    pub impacted: Vec<ImpactedNode>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RootCause {
+    pub node: NodeWithStatus,
+    /// How likely the node is the origin of errors, between 0 and 1.
+    pub score: f64,
+    /// Fraction of the calls to the node that failed unexpectedly.
+    pub inbound_error_ratio: f64,
+    /// Fraction of the calls made by the node that failed unexpectedly.
+    pub downstream_error_ratio: f64,
+    /// Unexpected errors anywhere in the graph that trace back to the node,
+    /// following failing calls downstream.
+    pub attributed_errors: u64,
+    pub explanation: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RootCauses {
+    /// Nodes receiving unexpected errors, most likely origin first.
+    pub candidates: Vec<RootCause>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EdgeChange {
+    pub before: CombinedEdge,
+    pub after: CombinedEdge,
+    /// Relative change of the calls per minute, `null` if there were no calls
+    /// before.
+    pub volume_change: Option<f64>,
+    /// Change of the fraction of calls failing unexpectedly.
+    pub error_ratio_change: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GraphDiff {
+    pub added_nodes: Vec<NodeWithStatus>,
+    pub removed_nodes: Vec<NodeWithStatus>,
+    pub added_edges: Vec<CombinedEdge>,
+    pub removed_edges: Vec<CombinedEdge>,
+    /// Edges in both windows whose volume or error ratio moved beyond the
+    /// thresholds.
+    pub changed_edges: Vec<EdgeChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cycle {
+    /// The services in the cycle.
+    pub nodes: Vec<NodeWithStatus>,
+    /// The service level edges between them.
+    pub edges: Vec<CombinedEdge>,
+    /// Calls on all edges of the cycle.
+    pub traffic: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cycles {
+    /// Busiest cycle first.
+    pub cycles: Vec<Cycle>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ActiveNodes {
     pub nodes: Vec<NodeActivity>,
+    /// Pass as `cursor` to get the next page, unset on the last page.
+    pub next_cursor: Option<Uuid>,
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -168,9 +867,39 @@ pub struct ServiceMapQueryParams {
     pub to_types: BTreeSet<NodeType>,
     #[serde(default)]
     pub edge_statuses: BTreeSet<EdgeStatus>,
+    #[serde(flatten)]
+    pub attributes: AttributeFilter,
+    #[serde(flatten)]
+    pub traffic: TrafficFilter,
 
+    /// How services and transactions are presented, the raw graph if unset.
     #[serde(default)]
-    pub traffic_volume: Option<u32>,
+    pub view: Option<GraphView>,
+    /// Include the statuses of transactions in those of their services.
+    #[serde(default, deserialize_with = "query::from_str")]
+    pub roll_up_transactions: bool,
+    /// Return centrality scores with every node, computed on the view.
+    #[serde(default, deserialize_with = "query::from_str")]
+    pub with_centrality: bool,
+    /// The `active_nodes.next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<Uuid>,
+}
+
+/// Levels of detail a graph can be presented at.
+///
+/// Every call is reported once between the services and once between the
+/// transactions involved (plus the mixed combinations), so the raw graph
+/// contains the same calls several times.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphView {
+    /// Only services, transactions are rolled up into their parent.
+    Service,
+    /// The most specific nodes of every call, i.e. transactions where known.
+    Transaction,
+    /// Like `Transaction` but including the parent services of transactions.
+    Mixed,
 }
 
 impl Deref for ServiceMapQueryParams {
@@ -183,11 +912,17 @@ impl Deref for ServiceMapQueryParams {
 
 impl From<ServiceMapQueryParams> for GraphQueryParams {
     fn from(query: ServiceMapQueryParams) -> GraphQueryParams {
+        // the service map filters traffic, rolls up statuses and computes
+        // centrality only after applying the view
         GraphQueryParams {
             common: query.common,
             from_types: query.from_types,
             to_types: query.to_types,
             edge_statuses: query.edge_statuses,
+            attributes: query.attributes,
+            traffic: TrafficFilter::default(),
+            with_centrality: false,
+            roll_up_transactions: false,
         }
     }
 }
@@ -199,6 +934,8 @@ impl From<ServiceMapQueryParams> for NodeQueryParams {
         NodeQueryParams {
             common: query.common,
             types,
+            cursor: query.cursor,
+            ..Default::default()
         }
     }
 }
@@ -207,6 +944,9 @@ impl From<ServiceMapQueryParams> for NodeQueryParams {
 pub struct ServiceMap {
     pub graph: Graph,
     pub active_nodes: ActiveNodes,
+    /// The graph was truncated or there are more active nodes, see
+    /// `graph.truncated` and `active_nodes.next_cursor`.
+    pub truncated: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -219,4 +959,42 @@ pub struct Histogram {
 pub struct Bucket {
     pub ts: DateTime<Utc>,
     pub n: u64,
+    pub status_ok: u64,
+    pub status_expected_error: u64,
+    pub status_unexpected_error: u64,
+    /// The counts were extrapolated from sampled edges.
+    pub extrapolated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpoolStats {
+    pub depth: u64,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Health {
+    pub status: String,
+    pub spool: SpoolStats,
+}
+
+/// A node on the other end of an edge together with the edge's statistics.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeDependency {
+    pub node: Node,
+    pub edge: CombinedEdge,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeDetail {
+    /// The node with the statuses of its inbound edges.
+    pub node: NodeWithStatus,
+    pub parent: Option<Node>,
+    pub children: Vec<Node>,
+    pub callers: Vec<NodeDependency>,
+    pub callees: Vec<NodeDependency>,
+    pub last_activity: Option<DateTime<Utc>>,
+    /// Statuses of the inbound edges over time.
+    pub series: Histogram,
 }
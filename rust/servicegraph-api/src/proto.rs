@@ -0,0 +1,141 @@
+//! Protobuf encoding of submissions, see `servicegraph.proto`.
+use std::convert::TryFrom;
+
+use chrono::{TimeZone, Utc};
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::payloads;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum NodeType {
+    Service = 0,
+    Transaction = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum EdgeStatus {
+    Ok = 0,
+    ExpectedError = 1,
+    UnexpectedError = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Node {
+    #[prost(bytes = "vec", tag = "1")]
+    pub node_id: Vec<u8>,
+    #[prost(enumeration = "NodeType", tag = "2")]
+    pub node_type: i32,
+    #[prost(string, tag = "3")]
+    pub name: String,
+    #[prost(string, optional, tag = "4")]
+    pub description: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub class: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub parent_id: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Edge {
+    /// Unix timestamp in milliseconds.
+    #[prost(int64, tag = "1")]
+    pub ts: i64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub from_node_id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub to_node_id: Vec<u8>,
+    #[prost(enumeration = "EdgeStatus", tag = "4")]
+    pub status: i32,
+    #[prost(uint32, tag = "5")]
+    pub n: u32,
+    #[prost(string, optional, tag = "6")]
+    pub description: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub class: Option<String>,
+    #[prost(double, optional, tag = "8")]
+    pub sample_rate: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitData {
+    #[prost(uint64, tag = "1")]
+    pub project_id: u64,
+    #[prost(message, repeated, tag = "2")]
+    pub nodes: Vec<Node>,
+    #[prost(message, repeated, tag = "3")]
+    pub edges: Vec<Edge>,
+    #[prost(string, optional, tag = "4")]
+    pub batch_id: Option<String>,
+}
+
+fn uuid_from_bytes(bytes: &[u8]) -> Result<Uuid, Error> {
+    Ok(Uuid::from_slice(bytes)?)
+}
+
+impl TryFrom<Node> for payloads::Node {
+    type Error = Error;
+
+    fn try_from(node: Node) -> Result<Self, Error> {
+        Ok(payloads::Node {
+            node_id: uuid_from_bytes(&node.node_id)?,
+            node_type: match NodeType::from_i32(node.node_type) {
+                Some(NodeType::Service) => payloads::NodeType::Service,
+                Some(NodeType::Transaction) => payloads::NodeType::Transaction,
+                None => return Err(anyhow::anyhow!("invalid node type {}", node.node_type)),
+            },
+            name: node.name,
+            description: node.description,
+            class: node.class,
+            parent_id: node.parent_id.as_deref().map(uuid_from_bytes).transpose()?,
+        })
+    }
+}
+
+impl TryFrom<Edge> for payloads::Edge {
+    type Error = Error;
+
+    fn try_from(edge: Edge) -> Result<Self, Error> {
+        Ok(payloads::Edge {
+            ts: Utc
+                .timestamp_millis_opt(edge.ts)
+                .single()
+                .ok_or_else(|| anyhow::anyhow!("invalid timestamp {}", edge.ts))?,
+            from_node_id: uuid_from_bytes(&edge.from_node_id)?,
+            to_node_id: uuid_from_bytes(&edge.to_node_id)?,
+            status: match EdgeStatus::from_i32(edge.status) {
+                Some(EdgeStatus::Ok) => payloads::EdgeStatus::Ok,
+                Some(EdgeStatus::ExpectedError) => payloads::EdgeStatus::ExpectedError,
+                Some(EdgeStatus::UnexpectedError) => payloads::EdgeStatus::UnexpectedError,
+                None => return Err(anyhow::anyhow!("invalid edge status {}", edge.status)),
+            },
+            n: edge.n,
+            description: edge.description,
+            class: edge.class,
+            sample_rate: edge.sample_rate.unwrap_or(1.0),
+        })
+    }
+}
+
+impl TryFrom<SubmitData> for payloads::SubmitData {
+    type Error = Error;
+
+    fn try_from(data: SubmitData) -> Result<Self, Error> {
+        Ok(payloads::SubmitData {
+            project_id: data.project_id,
+            batch_id: data.batch_id,
+            nodes: data
+                .nodes
+                .into_iter()
+                .map(payloads::Node::try_from)
+                .collect::<Result<_, _>>()?,
+            edges: data
+                .edges
+                .into_iter()
+                .map(payloads::Edge::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
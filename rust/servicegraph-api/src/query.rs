@@ -0,0 +1,209 @@
+//! Deserialization of query parameters into the JSON query payloads.
+//!
+//! Query strings only carry strings, so the GET variants of the query
+//! endpoints map them onto the same structs the POST variants deserialize
+//! from JSON.  Keys that hold a set in the payload (or that are repeated) turn
+//! into arrays, everything else stays a string.  Non-string fields accept
+//! strings through the helpers in this module.
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::error::Error;
+
+/// Request guard deserializing the query string into `T`.
+pub struct QueryParams<T>(pub T);
+
+fn parse_query<'a, T, I>(pairs: I) -> Result<T, Error>
+where
+    T: DeserializeOwned + Serialize + Default,
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    // the default value tells us which keys are sets
+    let shape = match serde_json::to_value(T::default())? {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+
+    let mut values: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (key, value) in pairs {
+        let key = key.trim_end_matches("[]");
+        values
+            .entry(key.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+
+    let mut object = Map::new();
+    for (key, mut items) in values {
+        let is_array = matches!(shape.get(&key), Some(Value::Array(_)));
+        let value = if is_array || items.len() > 1 {
+            Value::Array(items.into_iter().map(Value::String).collect())
+        } else {
+            Value::String(items.pop().unwrap_or_default())
+        };
+        object.insert(key, value);
+    }
+    Ok(serde_json::from_value(Value::Object(object))?)
+}
+
+#[rocket::async_trait]
+impl<'r, T> FromRequest<'r> for QueryParams<T>
+where
+    T: DeserializeOwned + Serialize + Default + Send,
+{
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let pairs = req
+            .uri()
+            .query()
+            .map(|query| query.segments().collect::<Vec<_>>())
+            .unwrap_or_default();
+        match parse_query(pairs) {
+            Ok(params) => Outcome::Success(QueryParams(params)),
+            Err(err) => Outcome::Failure((Status::BadRequest, err)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StrOrValue<T> {
+    Str(String),
+    Value(T),
+}
+
+impl<T> StrOrValue<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    fn into_value<E: de::Error>(self) -> Result<T, E> {
+        match self {
+            StrOrValue::Str(s) => s.trim().parse().map_err(E::custom),
+            StrOrValue::Value(value) => Ok(value),
+        }
+    }
+}
+
+/// Deserializes a value that may also be given as a string.
+pub fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    T::Err: Display,
+{
+    StrOrValue::deserialize(deserializer)?.into_value()
+}
+
+/// Deserializes an optional value that may also be given as a string.
+pub fn option_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    T::Err: Display,
+{
+    Option::<StrOrValue<T>>::deserialize(deserializer)?
+        .map(StrOrValue::into_value)
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dates::DateSpec;
+    use crate::payloads::{
+        EdgeStatus, GraphDiffQueryParams, GraphQueryParams, HistogramQueryParams, NodeType,
+        ServiceMapQueryParams,
+    };
+
+    #[test]
+    fn test_parse_query() {
+        let params: GraphQueryParams = parse_query(vec![
+            ("project_id", "42"),
+            ("start_date", "2021-06-09T00:00:00Z"),
+            ("from_types", "service"),
+            ("to_types", "service"),
+            ("to_types", "transaction"),
+            ("edge_statuses[]", "unexpected_error"),
+        ])
+        .unwrap();
+        assert_eq!(params.project_id, 42);
+        assert!(params.start_date.is_some());
+        assert_eq!(params.from_types.len(), 1);
+        assert!(params.to_types.contains(&NodeType::Transaction));
+        assert!(params.edge_statuses.contains(&EdgeStatus::UnexpectedError));
+
+        let params: ServiceMapQueryParams =
+            parse_query(vec![("project_id", "1"), ("traffic_volume", "20")]).unwrap();
+        assert_eq!(params.traffic.traffic_volume, Some(20));
+
+        let params: GraphQueryParams = parse_query(vec![
+            ("project_id", "1"),
+            ("top_edges", "20"),
+            ("min_error_ratio", "0.5"),
+        ])
+        .unwrap();
+        assert_eq!(params.traffic.top_edges, Some(20));
+        assert_eq!(params.traffic.min_error_ratio, Some(0.5));
+
+        let params: HistogramQueryParams = parse_query(vec![
+            ("project_id", "1"),
+            ("node_ids", "418f3d00-ba14-42eb-98b8-5f3fb1b975c8"),
+            ("to_node_id", "5042546b-07a0-41d4-a73c-9138722eebb4"),
+        ])
+        .unwrap();
+        assert_eq!(params.node_ids.len(), 1);
+        assert!(params.to_node_id.is_some());
+
+        let params: GraphQueryParams = parse_query(vec![
+            ("project_id", "1"),
+            ("start_date", "-7d"),
+            ("end_date", "today"),
+            ("timezone", "Europe/Vienna"),
+        ])
+        .unwrap();
+        assert_eq!(
+            params.start_date,
+            Some(DateSpec::Ago(chrono::Duration::days(7)))
+        );
+        assert_eq!(params.end_date, Some(DateSpec::Today));
+        assert_eq!(params.timezone, Some(chrono_tz::Europe::Vienna));
+        assert!(parse_query::<GraphQueryParams, _>(vec![("timezone", "Mars/Olympus")]).is_err());
+
+        assert!(parse_query::<GraphQueryParams, _>(vec![("project_id", "x")]).is_err());
+
+        // diff windows take relative dates in the viewer's timezone too
+        let params: GraphDiffQueryParams = parse_query(vec![
+            ("project_id", "1"),
+            ("before_start", "yesterday"),
+            ("before_end", "today"),
+            ("after_start", "-1h"),
+            ("timezone", "Europe/Vienna"),
+        ])
+        .unwrap();
+        let now = "2021-06-09T12:30:00Z".parse().unwrap();
+        let (before, after) = params.windows_at(now);
+        assert_eq!(
+            before.start_date,
+            Some(DateSpec::Absolute("2021-06-07T22:00:00Z".parse().unwrap()))
+        );
+        assert_eq!(
+            before.end_date,
+            Some(DateSpec::Absolute("2021-06-08T22:00:00Z".parse().unwrap()))
+        );
+        assert_eq!(
+            after.start_date,
+            Some(DateSpec::Absolute("2021-06-09T11:30:00Z".parse().unwrap()))
+        );
+        assert_eq!(after.end_date, Some(DateSpec::Absolute(now)));
+    }
+}
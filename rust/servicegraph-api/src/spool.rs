@@ -0,0 +1,351 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clickhouse_rs::errors::{DriverError, Error as ClickhouseError};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::{Build, Orbit, Rocket};
+use serde::Deserialize;
+
+use crate::db::{get_client, register_submission};
+use crate::error::Error;
+use crate::payloads::{SpoolStats, StoredSubmission};
+
+/// Configuration of the on-disk submission spool (read from `Rocket.toml`).
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpoolConfig {
+    #[serde(default = "default_spool_dir")]
+    pub spool_dir: PathBuf,
+    #[serde(default = "default_spool_max_bytes")]
+    pub spool_max_bytes: u64,
+    #[serde(default = "default_spool_replay_interval")]
+    pub spool_replay_interval: u64,
+}
+
+fn default_spool_dir() -> PathBuf {
+    PathBuf::from("spool")
+}
+
+fn default_spool_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_spool_replay_interval() -> u64 {
+    5
+}
+
+#[derive(Default)]
+struct SpoolState {
+    next_seq: u64,
+    depth: u64,
+    bytes: u64,
+}
+
+struct SpoolInner {
+    dir: PathBuf,
+    max_bytes: u64,
+    replay_interval: Duration,
+    state: Mutex<SpoolState>,
+}
+
+/// A write-ahead spool for submissions that could not be written to ClickHouse.
+///
+/// Every spooled submission is stored as its own file named after a monotonic
+/// sequence number so that replay happens in the order submissions came in.
+#[derive(Clone)]
+pub struct Spool {
+    inner: Arc<SpoolInner>,
+}
+
+fn entry_seq(path: &Path) -> Option<u64> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn write_entry(path: &Path, payload: &[u8]) -> Result<(), Error> {
+    // write to a temporary file first so that replay never sees a
+    // partially written entry.
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(payload)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+impl Spool {
+    pub fn open(config: &SpoolConfig) -> Result<Spool, Error> {
+        fs::create_dir_all(&config.spool_dir)?;
+        let mut state = SpoolState::default();
+        for entry in fs::read_dir(&config.spool_dir)? {
+            let entry = entry?;
+            // left over from a write that was interrupted by a crash
+            if entry.path().extension() == Some(OsStr::new("tmp")) {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            if let Some(seq) = entry_seq(&entry.path()) {
+                state.next_seq = state.next_seq.max(seq + 1);
+                state.depth += 1;
+                state.bytes += entry.metadata()?.len();
+            }
+        }
+        Ok(Spool {
+            inner: Arc::new(SpoolInner {
+                dir: config.spool_dir.clone(),
+                max_bytes: config.spool_max_bytes,
+                replay_interval: Duration::from_secs(config.spool_replay_interval.max(1)),
+                state: Mutex::new(state),
+            }),
+        })
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        let state = self.inner.state.lock().unwrap();
+        SpoolStats {
+            depth: state.depth,
+            bytes: state.bytes,
+            max_bytes: self.inner.max_bytes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.state.lock().unwrap().depth == 0
+    }
+
+    /// Appends a submission to the end of the spool.
+    pub async fn push(&self, data: &StoredSubmission) -> Result<(), Error> {
+        let payload = serde_json::to_vec(data)?;
+        let size = payload.len() as u64;
+        // the entry is accounted for up front so that the file can be written
+        // without holding the lock
+        let seq = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.bytes + size > self.inner.max_bytes {
+                return Err(anyhow::anyhow!(
+                    "database unavailable and spool is full ({} bytes)",
+                    state.bytes
+                ));
+            }
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.depth += 1;
+            state.bytes += size;
+            seq
+        };
+
+        let path = self.inner.dir.join(format!("{:020}.json", seq));
+        let result = match tokio::task::spawn_blocking(move || write_entry(&path, &payload)).await {
+            Ok(result) => result,
+            Err(err) => Err(err.into()),
+        };
+        if result.is_err() {
+            let mut state = self.inner.state.lock().unwrap();
+            state.depth = state.depth.saturating_sub(1);
+            state.bytes = state.bytes.saturating_sub(size);
+        }
+        result
+    }
+
+    fn oldest_entry(&self) -> Result<Option<PathBuf>, Error> {
+        let mut oldest: Option<(u64, PathBuf)> = None;
+        for entry in fs::read_dir(&self.inner.dir)? {
+            let path = entry?.path();
+            match (entry_seq(&path), &oldest) {
+                (Some(seq), Some((oldest_seq, _))) if seq > *oldest_seq => {}
+                (Some(seq), _) => oldest = Some((seq, path)),
+                (None, _) => {}
+            }
+        }
+        Ok(oldest.map(|(_, path)| path))
+    }
+
+    fn remove_entry(&self, path: &Path, quarantine: bool) -> Result<(), Error> {
+        let size = fs::metadata(path)?.len();
+        if quarantine {
+            fs::rename(path, path.with_extension("rejected"))?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        state.depth = state.depth.saturating_sub(1);
+        state.bytes = state.bytes.saturating_sub(size);
+        Ok(())
+    }
+
+    /// Writes spooled submissions to ClickHouse, oldest first.
+    ///
+    /// Stops at the first entry that cannot be written because the database
+    /// is unavailable.  Entries the database rejects are renamed to
+    /// `.rejected` so they don't block the rest of the spool.
+    pub async fn replay(&self) -> Result<usize, Error> {
+        let mut replayed = 0;
+        while let Some(path) = self.oldest_entry()? {
+            let data: StoredSubmission = match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("unreadable spool entry {}: {}", path.display(), err);
+                    self.remove_entry(&path, true)?;
+                    continue;
+                }
+            };
+            let mut client = get_client().await?;
+            match register_submission(&mut client, &data).await {
+                Ok(()) => {
+                    self.remove_entry(&path, false)?;
+                    replayed += 1;
+                }
+                Err(err) if is_unavailable(&err) => return Err(err),
+                Err(err) => {
+                    error!("rejected spool entry {}: {}", path.display(), err);
+                    self.remove_entry(&path, true)?;
+                }
+            }
+        }
+        Ok(replayed)
+    }
+
+    async fn replay_loop(self) {
+        loop {
+            tokio::time::sleep(self.inner.replay_interval).await;
+            if self.is_empty() {
+                continue;
+            }
+            match self.replay().await {
+                Ok(0) => {}
+                Ok(n) => info!("replayed {} spooled submissions", n),
+                Err(err) => warn!("spool replay paused: {}", err),
+            }
+        }
+    }
+}
+
+/// Returns `true` if the error means ClickHouse could not be reached, as
+/// opposed to the server or the driver rejecting the data.
+pub fn is_unavailable(err: &Error) -> bool {
+    matches!(
+        err.downcast_ref::<ClickhouseError>(),
+        Some(ClickhouseError::Io(_))
+            | Some(ClickhouseError::Connection(_))
+            | Some(ClickhouseError::Driver(DriverError::Timeout))
+    )
+}
+
+/// Opens the spool on ignite and replays it in the background after liftoff.
+pub struct SpoolFairing;
+
+#[rocket::async_trait]
+impl Fairing for SpoolFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Submission spool",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config: SpoolConfig = match rocket.figment().extract() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("invalid spool config: {}", err);
+                return Err(rocket);
+            }
+        };
+        match Spool::open(&config) {
+            Ok(spool) => Ok(rocket.manage(spool)),
+            Err(err) => {
+                error!(
+                    "failed to open spool at {}: {}",
+                    config.spool_dir.display(),
+                    err
+                );
+                Err(rocket)
+            }
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(spool) = rocket.state::<Spool>() {
+            tokio::spawn(spool.clone().replay_loop());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payloads::SubmitData;
+    use clickhouse_rs::errors::FromSqlError;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_push_and_reopen() {
+        let config = SpoolConfig {
+            spool_dir: std::env::temp_dir().join(format!("spool-{}", Uuid::new_v4())),
+            spool_max_bytes: 1024,
+            spool_replay_interval: 1,
+        };
+        let spool = Spool::open(&config).unwrap();
+        assert!(spool.is_empty());
+
+        for project_id in 1..=3 {
+            let data = SubmitData {
+                project_id,
+                ..Default::default()
+            };
+            spool.push(&data.into()).await.unwrap();
+        }
+        assert_eq!(spool.stats().depth, 3);
+
+        // interrupted writes are cleaned up
+        let tmp_path = config.spool_dir.join(format!("{:020}.tmp", 3));
+        fs::write(&tmp_path, b"{").unwrap();
+
+        // a reopened spool picks up where the old one left off
+        let spool = Spool::open(&config).unwrap();
+        assert_eq!(spool.stats().depth, 3);
+        assert!(!tmp_path.exists());
+        let oldest = spool.oldest_entry().unwrap().unwrap();
+        let data: StoredSubmission = serde_json::from_slice(&fs::read(&oldest).unwrap()).unwrap();
+        assert_eq!(data.data.project_id, 1);
+
+        spool.remove_entry(&oldest, false).unwrap();
+        assert_eq!(spool.stats().depth, 2);
+
+        // nothing more fits once the configured size is reached
+        let full_config = SpoolConfig {
+            spool_max_bytes: spool.stats().bytes,
+            ..config.clone()
+        };
+        let spool = Spool::open(&full_config).unwrap();
+        let data = SubmitData {
+            project_id: 4,
+            ..Default::default()
+        };
+        assert!(spool.push(&data.into()).await.is_err());
+        assert_eq!(spool.stats().depth, 2);
+
+        fs::remove_dir_all(&config.spool_dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_unavailable() {
+        let unavailable: Error = ClickhouseError::Driver(DriverError::Timeout).into();
+        assert!(is_unavailable(&unavailable));
+        let unavailable: Error =
+            ClickhouseError::Io(std::io::ErrorKind::ConnectionRefused.into()).into();
+        assert!(is_unavailable(&unavailable));
+
+        // retrying would not help with these
+        let rejected: Error = ClickhouseError::FromSql(FromSqlError::OutOfRange).into();
+        assert!(!is_unavailable(&rejected));
+        let rejected: Error = ClickhouseError::Driver(DriverError::UnexpectedPacket).into();
+        assert!(!is_unavailable(&rejected));
+        assert!(!is_unavailable(&anyhow::anyhow!("invalid batch")));
+    }
+}
@@ -0,0 +1,194 @@
+use std::mem;
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+use crate::payloads::{StoredSubmission, SubmitData, TimestampDecisions};
+
+/// What happens to edges with a timestamp too far in the future.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FuturePolicy {
+    /// Move the edge to the time it was received.
+    Clamp,
+    /// Drop the edge.
+    Reject,
+}
+
+/// What happens to edges with a timestamp too far in the past.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LatePolicy {
+    /// Write the edge to the `edges_backfill` table instead of `edges`.
+    Backfill,
+    /// Drop the edge.
+    Reject,
+}
+
+/// Acceptance window for edge timestamps (read from `Rocket.toml`).
+#[derive(Deserialize, Debug, Clone)]
+pub struct TimestampConfig {
+    /// How far ahead of the server clock an edge may be, in seconds.
+    #[serde(default = "default_max_future_skew")]
+    pub max_future_skew: u64,
+    #[serde(default = "default_future_policy")]
+    pub future_policy: FuturePolicy,
+    /// How far behind the server clock an edge may be, in seconds.
+    #[serde(default = "default_max_late")]
+    pub max_late: u64,
+    #[serde(default = "default_late_policy")]
+    pub late_policy: LatePolicy,
+}
+
+fn default_max_future_skew() -> u64 {
+    5 * 60
+}
+
+fn default_future_policy() -> FuturePolicy {
+    FuturePolicy::Clamp
+}
+
+fn default_max_late() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_late_policy() -> LatePolicy {
+    LatePolicy::Backfill
+}
+
+impl TimestampConfig {
+    /// Sorts the edges of a submission according to the policy.
+    ///
+    /// Edges that are too far in the future are clamped or removed, late
+    /// edges are removed or moved to `backfill_edges`.
+    pub fn apply(
+        &self,
+        now: DateTime<Utc>,
+        mut data: SubmitData,
+    ) -> (StoredSubmission, TimestampDecisions) {
+        let mut decisions = TimestampDecisions::default();
+        let future_bound = now + Duration::seconds(self.max_future_skew as i64);
+        let late_bound = now - Duration::seconds(self.max_late as i64);
+
+        let mut edges = Vec::with_capacity(data.edges.len());
+        let mut backfill_edges = Vec::new();
+        for mut edge in mem::take(&mut data.edges) {
+            if edge.ts > future_bound {
+                match self.future_policy {
+                    FuturePolicy::Clamp => {
+                        edge.ts = now;
+                        decisions.clamped += 1;
+                        edges.push(edge);
+                    }
+                    FuturePolicy::Reject => decisions.rejected_future += 1,
+                }
+            } else if edge.ts < late_bound {
+                match self.late_policy {
+                    LatePolicy::Backfill => {
+                        decisions.backfilled += 1;
+                        backfill_edges.push(edge);
+                    }
+                    LatePolicy::Reject => decisions.rejected_late += 1,
+                }
+            } else {
+                decisions.accepted += 1;
+                edges.push(edge);
+            }
+        }
+        data.edges = edges;
+        (
+            StoredSubmission {
+                data,
+                backfill_edges,
+            },
+            decisions,
+        )
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Timestamp policy", |rocket| async {
+        match rocket.figment().extract::<TimestampConfig>() {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(err) => {
+                error!("invalid timestamp policy: {}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payloads::{Edge, EdgeStatus};
+    use uuid::Uuid;
+
+    fn edge_at(ts: DateTime<Utc>) -> Edge {
+        Edge {
+            ts,
+            from_node_id: Uuid::new_v4(),
+            to_node_id: Uuid::new_v4(),
+            status: EdgeStatus::Ok,
+            n: 1,
+            description: None,
+            class: None,
+            sample_rate: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let now = Utc::now();
+        let mut config = TimestampConfig {
+            max_future_skew: 60,
+            future_policy: FuturePolicy::Clamp,
+            max_late: 3600,
+            late_policy: LatePolicy::Backfill,
+        };
+        let edges = || {
+            vec![
+                edge_at(now),
+                edge_at(now + Duration::seconds(30)),
+                edge_at(now + Duration::days(365)),
+                edge_at(now - Duration::minutes(30)),
+                edge_at(now - Duration::days(2)),
+            ]
+        };
+
+        let data = SubmitData {
+            project_id: 1,
+            edges: edges(),
+            ..Default::default()
+        };
+        let (stored, decisions) = config.apply(now, data);
+        assert_eq!(decisions.accepted, 3);
+        assert_eq!(decisions.clamped, 1);
+        assert_eq!(decisions.backfilled, 1);
+        assert_eq!(stored.data.edges.len(), 4);
+        assert_eq!(stored.data.edges[2].ts, now);
+        assert_eq!(stored.backfill_edges.len(), 1);
+
+        config.future_policy = FuturePolicy::Reject;
+        config.late_policy = LatePolicy::Reject;
+        let data = SubmitData {
+            project_id: 1,
+            edges: edges(),
+            ..Default::default()
+        };
+        let (stored, decisions) = config.apply(now, data);
+        assert_eq!(decisions.accepted, 3);
+        assert_eq!(decisions.rejected_future, 1);
+        assert_eq!(decisions.rejected_late, 1);
+        assert_eq!(stored.data.edges.len(), 3);
+        assert!(stored.backfill_edges.is_empty());
+        // late edges cannot be sent as backfill by clients
+        assert!(serde_json::from_str::<SubmitData>(
+            r#"{"project_id": 1, "backfill_edges": [{"bogus": true}]}"#
+        )
+        .unwrap()
+        .edges
+        .is_empty());
+    }
+}
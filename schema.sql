@@ -1,5 +1,12 @@
 CREATE DATABASE IF NOT EXISTS servicegraph;
 
+-- migrations/ that were applied, see `make migrate`
+CREATE TABLE IF NOT EXISTS servicegraph.schema_migrations (
+    version String,
+    ts DateTime
+) ENGINE = ReplacingMergeTree(ts)
+ORDER BY version;
+
 CREATE TABLE IF NOT EXISTS servicegraph.nodes (
     project_id UInt64,
     node_id UUID,
@@ -21,12 +28,19 @@ CREATE TABLE IF NOT EXISTS servicegraph.edges (
     description Nullable(String),
     class Nullable(String),
     status UInt8,
-    n UInt32
+    n UInt32,
+    -- fraction of calls the client reported, counts are upscaled by 1 / sample_rate
+    -- (saturating at the largest UInt32)
+    sample_rate Float32 DEFAULT 1
 ) ENGINE = MergeTree()
 PARTITION BY (toYYYYMMDD(ts), project_id, from_node_id)
 ORDER BY (project_id, ts)
 TTL ts + toIntervalDay(90);
 
+-- late edges routed here by the timestamp policy, kept apart from the live
+-- edges but aggregated into edges_by_minute all the same
+CREATE TABLE IF NOT EXISTS servicegraph.edges_backfill AS servicegraph.edges;
+
 CREATE TABLE IF NOT EXISTS servicegraph.edges_by_minute (
     -- timestamp bucketed by minute
     project_id UInt64,
@@ -37,11 +51,24 @@ CREATE TABLE IF NOT EXISTS servicegraph.edges_by_minute (
     class Nullable(String),
     status_ok AggregateFunction(sumIf, UInt32, UInt8),
     status_expected_error AggregateFunction(sumIf, UInt32, UInt8),
-    status_unexpected_error AggregateFunction(sumIf, UInt32, UInt8)
+    status_unexpected_error AggregateFunction(sumIf, UInt32, UInt8),
+    -- 1 if any of the counts were extrapolated from sampled edges
+    extrapolated SimpleAggregateFunction(max, UInt8)
 ) ENGINE = AggregatingMergeTree()
 ORDER BY (project_id, ts, from_node_id, to_node_id)
 TTL ts + toIntervalDay(90);
 
+ALTER TABLE servicegraph.edges
+    ADD COLUMN IF NOT EXISTS sample_rate Float32 DEFAULT 1;
+
+ALTER TABLE servicegraph.edges_backfill
+    ADD COLUMN IF NOT EXISTS sample_rate Float32 DEFAULT 1;
+
+ALTER TABLE servicegraph.edges_by_minute
+    ADD COLUMN IF NOT EXISTS extrapolated SimpleAggregateFunction(max, UInt8);
+
+-- changes to the views of existing databases go into migrations/, dropping
+-- and recreating them here would lose the edges inserted in between
 
 CREATE MATERIALIZED VIEW IF NOT EXISTS servicegraph.edges_by_minute_mv TO servicegraph.edges_by_minute
 AS SELECT
@@ -51,8 +78,24 @@ AS SELECT
     to_node_id,
     argMax(description, ts) as description,
     argMax(class, ts) as class,
-    sumIfState(n, status = 1) as status_ok,
-    sumIfState(n, status = 2) as status_expected_error,
-    sumIfState(n, status = 3) as status_unexpected_error
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 1) as status_ok,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 2) as status_expected_error,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 3) as status_unexpected_error,
+    max(toUInt8(sample_rate < 1)) as extrapolated
 FROM servicegraph.edges
 GROUP BY project_id, from_node_id, to_node_id, ts;
+
+CREATE MATERIALIZED VIEW IF NOT EXISTS servicegraph.edges_backfill_by_minute_mv TO servicegraph.edges_by_minute
+AS SELECT
+    project_id,
+    toStartOfMinute(ts) AS ts,
+    from_node_id,
+    to_node_id,
+    argMax(description, ts) as description,
+    argMax(class, ts) as class,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 1) as status_ok,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 2) as status_expected_error,
+    sumIfState(toUInt32(least(round(n / sample_rate), 4294967295)), status = 3) as status_unexpected_error,
+    max(toUInt8(sample_rate < 1)) as extrapolated
+FROM servicegraph.edges_backfill
+GROUP BY project_id, from_node_id, to_node_id, ts;